@@ -0,0 +1,3 @@
+fn main() {
+    reaper_theme_packer::lsp_main();
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+};
+
+use ini::Ini;
+use log::debug;
+use relative_path::RelativePathBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    diagnostics::Diagnostic,
+    preprocess::{PreprocessOutput, SourceLocation},
+    theme::{ImageCheck, Resource, ResourceMap},
+};
+
+/// Changed when the format of the cache changes, so old caches are ignored
+const CACHE_VERSION: u32 = 4;
+
+pub fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Hash a list of strings, keeping the boundaries between them
+pub fn hash_parts(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|x| x.to_string_lossy().to_string())
+        .collect();
+    hash_parts(&paths.iter().map(|x| x.as_str()).collect::<Vec<_>>())
+}
+
+/// Everything the preprocessor read, to check whether its output is still up to date
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Inputs {
+    /// hash of each file that was read
+    files: BTreeMap<PathBuf, String>,
+    /// files that were looked for but couldn't be read, e.g. module paths tried by `require()`
+    absent: BTreeSet<PathBuf>,
+    /// hash of the paths matched by each resource pattern
+    globs: BTreeMap<String, String>,
+    /// environment variables read with `env()`
+    env: BTreeMap<String, Option<String>>,
+}
+
+impl Inputs {
+    pub fn file(&mut self, path: &Path, bytes: &[u8]) {
+        self.files.insert(path.to_path_buf(), hash(bytes));
+    }
+
+    pub fn absent(&mut self, path: &Path) {
+        self.absent.insert(path.to_path_buf());
+    }
+
+    pub fn glob(&mut self, pattern: &str, paths: &[PathBuf]) {
+        self.globs.insert(pattern.to_string(), hash_paths(paths));
+    }
+
+    pub fn env(&mut self, name: &str, value: Option<&str>) {
+        self.env
+            .insert(name.to_string(), value.map(|x| x.to_string()));
+    }
+
+    /// Check that every file, resource pattern and environment variable is unchanged, and that
+    /// missing files are still missing
+    fn is_up_to_date(&self) -> bool {
+        let files = self.files.iter().all(|(path, expected)| {
+            std::fs::read(path).is_ok_and(|bytes| hash(&bytes) == *expected)
+        });
+        let absent = || self.absent.iter().all(|path| !path.is_file());
+        let globs = || {
+            self.globs.iter().all(|(pattern, expected)| {
+                let Ok(paths) = glob::glob(pattern) else {
+                    return false;
+                };
+                let paths: Vec<PathBuf> = paths.filter_map(|x| x.ok()).collect();
+                hash_paths(&paths) == *expected
+            })
+        };
+        let env = || {
+            self.env
+                .iter()
+                .all(|(name, expected)| std::env::var(name).ok() == *expected)
+        };
+        files && absent() && globs() && env()
+    }
+}
+
+/// Record an input of the preprocessor in the Lua app data, if inputs are being recorded
+pub fn record(lua: &mlua::Lua, f: impl FnOnce(&mut Inputs)) {
+    if let Some(mut inputs) = lua.app_data_mut::<Inputs>() {
+        f(&mut inputs);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum CachedResource {
+    File(PathBuf),
+    /// hash of the generated bytes, stored in the blobs folder
+    Generated(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedPreprocess {
+    version: u32,
+    key: String,
+    inputs: Inputs,
+    rtconfig: String,
+    reapertheme: String,
+    resources: BTreeMap<String, CachedResource>,
+    source_map: Vec<SourceLocation>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// A folder with the output of the last preprocessor run, and compressed resources keyed by the
+/// hash of their contents
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// The cache of one output file in a folder shared by several themes, so building one theme
+    /// doesn't prune the entries of another
+    pub fn for_output(dir: &Path, output: &Path) -> Self {
+        let output = std::path::absolute(output).unwrap_or_else(|_| output.to_path_buf());
+        let name = output
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let hash = hash(output.to_string_lossy().as_bytes());
+        Self::new(&dir.join(format!("{}-{}", name, &hash[..16])))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn preprocess_path(&self) -> PathBuf {
+        self.dir.join("preprocess.json")
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("blobs").join(hash)
+    }
+
+    fn entries_dir(&self) -> PathBuf {
+        self.dir.join("entries")
+    }
+
+    fn image_checks_dir(&self) -> PathBuf {
+        self.dir.join("images")
+    }
+
+    /// Load the preprocessor output, if it was made with the same key and none of its inputs changed
+    pub fn load_preprocess(&self, key: &str) -> Option<PreprocessOutput> {
+        let text = std::fs::read_to_string(self.preprocess_path()).ok()?;
+        let cached: CachedPreprocess = serde_json::from_str(&text).ok()?;
+        if cached.version != CACHE_VERSION || cached.key != key {
+            debug!("cached preprocessor output has a different key");
+            return None;
+        }
+        if !cached.inputs.is_up_to_date() {
+            debug!("inputs of the cached preprocessor output changed");
+            return None;
+        }
+
+        let mut resources = ResourceMap::new();
+        for (dest, resource) in cached.resources {
+            let resource = match resource {
+                CachedResource::File(path) => Resource::File(path),
+                CachedResource::Generated(hash) => {
+                    Resource::Generated(std::fs::read(self.blob_path(&hash)).ok()?)
+                }
+            };
+            resources.insert(RelativePathBuf::from(dest), resource);
+        }
+
+        Some(PreprocessOutput {
+            rtconfig: cached.rtconfig,
+            reapertheme: Ini::load_from_str(&cached.reapertheme).ok()?,
+            resources,
+            source_map: cached.source_map,
+            inputs: cached.inputs,
+            diagnostics: cached.diagnostics,
+        })
+    }
+
+    pub fn save_preprocess(&self, key: &str, output: &PreprocessOutput) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.dir.join("blobs"))?;
+
+        let mut resources = BTreeMap::new();
+        let mut blobs = HashSet::new();
+        for (dest, resource) in output.resources.iter() {
+            let cached = match resource {
+                Resource::File(path) => CachedResource::File(path.clone()),
+                Resource::Generated(bytes) => {
+                    let hash = hash(bytes);
+                    std::fs::write(self.blob_path(&hash), bytes)?;
+                    blobs.insert(hash.clone());
+                    CachedResource::Generated(hash)
+                }
+            };
+            resources.insert(dest.to_string(), cached);
+        }
+
+        let mut reapertheme = Vec::new();
+        output.reapertheme.write_to(&mut reapertheme)?;
+
+        let cached = CachedPreprocess {
+            version: CACHE_VERSION,
+            key: key.to_string(),
+            inputs: output.inputs.clone(),
+            rtconfig: output.rtconfig.clone(),
+            reapertheme: String::from_utf8_lossy(&reapertheme).to_string(),
+            resources,
+            source_map: output.source_map.clone(),
+            diagnostics: output.diagnostics.clone(),
+        };
+        std::fs::write(
+            self.preprocess_path(),
+            serde_json::to_string(&cached).map_err(std::io::Error::other)?,
+        )?;
+
+        remove_unused(&self.dir.join("blobs"), &blobs);
+        Ok(())
+    }
+
+    /// Load a compressed resource entry saved by a previous build
+    pub fn load_entry(&self, key: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.entries_dir().join(key)).ok()
+    }
+
+    pub fn save_entry(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.entries_dir())?;
+        std::fs::write(self.entries_dir().join(key), data)
+    }
+
+    /// Load the result of checking a PNG file, saved under the key of its compressed entry
+    pub fn load_image_check(&self, key: &str) -> Option<ImageCheck> {
+        let text = std::fs::read_to_string(self.image_checks_dir().join(key)).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    pub fn save_image_check(&self, key: &str, check: &ImageCheck) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.image_checks_dir())?;
+        std::fs::write(
+            self.image_checks_dir().join(key),
+            serde_json::to_string(check).map_err(std::io::Error::other)?,
+        )
+    }
+
+    /// Remove compressed resource entries and image checks that weren't used by the last build
+    pub fn prune_entries(&self, used: &HashSet<String>) {
+        remove_unused(&self.entries_dir(), used);
+        remove_unused(&self.image_checks_dir(), used);
+    }
+}
+
+/// Remove the files in a folder whose names aren't in `used`
+fn remove_unused(dir: &Path, used: &HashSet<String>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|x| x.ok()) {
+        if !used.contains(entry.file_name().to_string_lossy().as_ref()) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_hash_parts() {
+        assert_ne!(hash_parts(&["ab", "c"]), hash_parts(&["a", "bc"]));
+        assert_eq!(hash_parts(&["ab", "c"]), hash_parts(&["ab", "c"]));
+    }
+
+    #[test]
+    fn test_inputs_up_to_date() {
+        let dir = TempDir::new("cache-inputs");
+        let file = dir.join("index.rtconfig.txt");
+        std::fs::write(&file, "set a b").unwrap();
+        std::fs::write(dir.join("a.png"), "").unwrap();
+        let pattern = dir.join("*.png").to_string_lossy().to_string();
+
+        let mut inputs = Inputs::default();
+        inputs.file(&file, b"set a b");
+        inputs.glob(&pattern, &[dir.join("a.png")]);
+        inputs.env("REAPER_THEME_PACKER_TEST_UNSET", None);
+        assert!(inputs.is_up_to_date());
+
+        std::fs::write(dir.join("b.png"), "").unwrap();
+        assert!(!inputs.is_up_to_date());
+        inputs.glob(&pattern, &[dir.join("a.png"), dir.join("b.png")]);
+        assert!(inputs.is_up_to_date());
+
+        inputs.absent(&dir.join("module.lua"));
+        assert!(inputs.is_up_to_date());
+        std::fs::write(dir.join("module.lua"), "").unwrap();
+        assert!(!inputs.is_up_to_date());
+
+        std::fs::write(&file, "set a c").unwrap();
+        assert!(!inputs.is_up_to_date());
+    }
+
+    #[test]
+    fn test_inputs_missing_files() {
+        let dir = TempDir::new("cache-missing");
+        let input = dir.join("index.rtconfig.txt");
+        std::fs::write(
+            &input,
+            "set a #{pcall(require, 'palette') and 1 or 2}\n\
+             set b #{pcall(load_image, 'a.png') and 1 or 2}\n",
+        )
+        .unwrap();
+
+        let output = crate::preprocess::preprocess(&input, None, &Default::default()).unwrap();
+        assert!(output.inputs.is_up_to_date());
+        std::fs::write(dir.join("palette.lua"), "return {}").unwrap();
+        assert!(!output.inputs.is_up_to_date());
+
+        let output = crate::preprocess::preprocess(&input, None, &Default::default()).unwrap();
+        assert!(output.inputs.is_up_to_date());
+        std::fs::write(dir.join("a.png"), "").unwrap();
+        assert!(!output.inputs.is_up_to_date());
+    }
+
+    #[test]
+    fn test_preprocess_roundtrip() {
+        let dir = TempDir::new("cache-preprocess");
+        let cache = Cache::new(&dir.join("cache"));
+        let input = dir.join("index.rtconfig.txt");
+        std::fs::write(&input, "set a b").unwrap();
+
+        let mut resources = ResourceMap::new();
+        resources.insert("a.png".into(), Resource::File(dir.join("a.png")));
+        resources.insert("b.bin".into(), Resource::Generated(vec![1, 2, 3]));
+        resources.insert("c.txt".into(), Resource::Generated(b"text".to_vec()));
+        let mut reapertheme = Ini::new();
+        reapertheme.with_section(Some("color theme")).set("a", "1");
+        let mut inputs = Inputs::default();
+        inputs.file(&input, b"set a b");
+
+        let output = PreprocessOutput {
+            rtconfig: "set a b".into(),
+            reapertheme,
+            resources,
+            source_map: vec![SourceLocation {
+                file: input.clone(),
+                line: 1,
+            }],
+            inputs,
+            diagnostics: vec![Diagnostic::warning("resource-overwrite", "overwritten")],
+        };
+        cache.save_preprocess("key", &output).unwrap();
+
+        assert!(cache.load_preprocess("other key").is_none());
+        let loaded = cache.load_preprocess("key").unwrap();
+        assert_eq!(loaded.rtconfig, output.rtconfig);
+        assert_eq!(loaded.resources, output.resources);
+        assert_eq!(loaded.source_map, output.source_map);
+        assert_eq!(loaded.diagnostics, output.diagnostics);
+        assert_eq!(
+            loaded.reapertheme.get_from(Some("color theme"), "a"),
+            Some("1")
+        );
+
+        std::fs::write(&input, "set a c").unwrap();
+        assert!(cache.load_preprocess("key").is_none());
+    }
+
+    #[test]
+    fn test_for_output() {
+        let dir = TempDir::new("cache-outputs");
+        let a = Cache::for_output(&dir, &dir.join("a.ReaperThemeZip"));
+        let b = Cache::for_output(&dir, &dir.join("b/a.ReaperThemeZip"));
+        assert_ne!(a.dir(), b.dir());
+        assert_eq!(
+            a.dir(),
+            Cache::for_output(&dir, &dir.join("a.ReaperThemeZip")).dir()
+        );
+
+        a.save_entry("a", b"first").unwrap();
+        b.save_entry("b", b"second").unwrap();
+        b.prune_entries(&HashSet::from(["b".to_string()]));
+        assert_eq!(a.load_entry("a").unwrap(), b"first");
+    }
+
+    #[test]
+    fn test_entries() {
+        let dir = TempDir::new("cache-entries");
+        let cache = Cache::new(&dir);
+        cache.save_entry("a", b"first").unwrap();
+        cache.save_entry("b", b"second").unwrap();
+        assert_eq!(cache.load_entry("a").unwrap(), b"first");
+        assert_eq!(cache.load_entry("c"), None);
+
+        cache.prune_entries(&HashSet::from(["b".to_string()]));
+        assert_eq!(cache.load_entry("a"), None);
+        assert_eq!(cache.load_entry("b").unwrap(), b"second");
+    }
+}
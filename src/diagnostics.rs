@@ -0,0 +1,204 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use clap::ValueEnum;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::parser::ErrorLocation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MessageFormat {
+    /// Human-readable messages through the logger
+    #[default]
+    Human,
+    /// One JSON object per line on stdout
+    Json,
+}
+
+static MESSAGE_FORMAT: Mutex<MessageFormat> = Mutex::new(MessageFormat::Human);
+
+pub fn set_message_format(format: MessageFormat) {
+    *MESSAGE_FORMAT.lock().unwrap() = format;
+}
+
+fn message_format() -> MessageFormat {
+    *MESSAGE_FORMAT.lock().unwrap()
+}
+
+thread_local! {
+    /// Diagnostics emitted on this thread inside [`record`]
+    static RECORDED: RefCell<Option<Vec<Diagnostic>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, and return the diagnostics it emitted along with its result, e.g. to repeat the
+/// warnings of a step whose output is cached
+pub fn record<T>(f: impl FnOnce() -> T) -> (T, Vec<Diagnostic>) {
+    let previous = RECORDED.replace(Some(Vec::new()));
+    let result = f();
+    let recorded = RECORDED.replace(previous).unwrap_or_default();
+    (result, recorded)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    /// A report asked for by an option, e.g. the size saved by `--optimize-png`
+    Note,
+}
+
+/// A single error, warning or note, in a form that can be printed for humans or serialised as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Cow<'static, str>,
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            code: code.into(),
+            file: None,
+            line: None,
+            column: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn error(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, code, message)
+    }
+
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Warning, code, message)
+    }
+
+    pub fn note(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Note, code, message)
+    }
+
+    pub fn file(mut self, path: &Path) -> Self {
+        self.file = Some(path.to_path_buf());
+        self
+    }
+
+    /// Set the line and column. The default location (line 0) is treated as "no location".
+    pub fn location(mut self, location: &ErrorLocation) -> Self {
+        if location.line > 0 {
+            self.line = Some(location.line);
+            self.column = Some(location.column_utf8);
+        }
+        self
+    }
+
+    pub fn emit(&self) {
+        RECORDED.with_borrow_mut(|recorded| {
+            if let Some(recorded) = recorded {
+                recorded.push(self.clone());
+            }
+        });
+        match message_format() {
+            MessageFormat::Human => match self.severity {
+                Severity::Error => error!("{}", self),
+                Severity::Warning => warn!("{}", self),
+                // notes were asked for, so they are printed whatever the log level
+                Severity::Note => eprintln!("{}", self),
+            },
+            MessageFormat::Json => {
+                println!("{}", serde_json::to_string(self).unwrap());
+            }
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+            if let (Some(line), Some(column)) = (self.line, self.column) {
+                write!(f, "{}:{}:", line, column)?;
+            }
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let diag = Diagnostic::error("read-error", "failed to read file").file("a.txt".as_ref());
+        assert_eq!(diag.to_string(), "a.txt: failed to read file");
+
+        let location = ErrorLocation {
+            line: 3,
+            column_utf8: 7,
+            ..Default::default()
+        };
+        let diag = Diagnostic::warning("foo", "bar")
+            .file("a.txt".as_ref())
+            .location(&location);
+        assert_eq!(diag.to_string(), "a.txt:3:7: bar");
+
+        let diag = Diagnostic::warning("foo", "bar")
+            .file("a.txt".as_ref())
+            .location(&ErrorLocation::default());
+        assert_eq!(diag.to_string(), "a.txt: bar");
+    }
+
+    #[test]
+    fn test_json() {
+        let location = ErrorLocation {
+            line: 3,
+            column_utf8: 7,
+            ..Default::default()
+        };
+        let diag = Diagnostic::warning("resource-overwrite", "bar")
+            .file("a.txt".as_ref())
+            .location(&location);
+        assert_eq!(
+            serde_json::to_string(&diag).unwrap(),
+            r#"{"severity":"warning","code":"resource-overwrite","file":"a.txt","line":3,"column":7,"message":"bar"}"#
+        );
+
+        let diag = Diagnostic::note("png-optimized", "optimized");
+        assert_eq!(
+            serde_json::to_string(&diag).unwrap(),
+            r#"{"severity":"note","code":"png-optimized","file":null,"line":null,"column":null,"message":"optimized"}"#
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let (result, recorded) = record(|| {
+            Diagnostic::warning("foo", "bar").emit();
+            1
+        });
+        assert_eq!(result, 1);
+        assert_eq!(recorded, vec![Diagnostic::warning("foo", "bar")]);
+
+        // only while recording
+        Diagnostic::warning("foo", "baz").emit();
+        let ((), recorded) = record(|| ());
+        assert!(recorded.is_empty());
+
+        let json = serde_json::to_string(&Diagnostic::warning("foo", "bar")).unwrap();
+        let diag: Diagnostic = serde_json::from_str(&json).unwrap();
+        assert_eq!(diag, Diagnostic::warning("foo", "bar"));
+    }
+}
@@ -0,0 +1,777 @@
+use std::{collections::HashSet, path::Path};
+
+use log::debug;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    diagnostics::Diagnostic,
+    theme::{Resource, ResourceMap},
+};
+
+/// Color of the guide pixels marking the margins of a stretchable image
+pub const PINK: [u8; 4] = [255, 0, 255, 255];
+/// Color of the guide pixels marking the area an image may draw outside of its bounds
+pub const YELLOW: [u8; 4] = [255, 255, 0, 255];
+
+/// Folders that REAPER loads scaled copies of images from, with their scale
+pub const SCALE_FOLDERS: [(&str, f64); 2] = [("150", 1.5), ("200", 2.0)];
+
+/// Largest width or height of a loaded or generated image
+pub const MAX_SIZE: u32 = 16384;
+
+/// Largest number of pixels in a loaded or generated image. Pixels are stored outside the Lua
+/// interpreter, so they don't count towards `--lua-memory-limit`
+pub const MAX_PIXELS: u64 = 4096 * 4096;
+
+#[derive(Error, Debug)]
+#[error(
+    "image size {0}x{1} is too large, the maximum is {MAX_SIZE} pixels per side and {MAX_PIXELS} \
+     pixels in total"
+)]
+pub struct SizeError(u64, u64);
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error(transparent)]
+    Png(#[from] png::DecodingError),
+    #[error(transparent)]
+    Size(#[from] SizeError),
+}
+
+/// Number of pixels in an image of the given size, if it isn't too large
+fn pixel_count(width: u64, height: u64) -> Result<usize, SizeError> {
+    if width > MAX_SIZE as u64 || height > MAX_SIZE as u64 || width * height > MAX_PIXELS {
+        return Err(SizeError(width, height));
+    }
+    usize::try_from(width * height).map_err(|_| SizeError(width, height))
+}
+
+/// A decoded image with 8-bit RGBA pixels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+/// Format of a PNG file before it was converted to RGBA
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Format {
+    pub color_type: png::ColorType,
+    pub bit_depth: png::BitDepth,
+}
+
+impl Image {
+    pub fn decode(bytes: &[u8]) -> Result<(Image, Format), DecodeError> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        pixel_count(reader.info().width as u64, reader.info().height as u64)?;
+        let format = Format {
+            color_type: reader.info().color_type,
+            bit_depth: reader.info().bit_depth,
+        };
+
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+        let buf = &buf[..frame.buffer_size()];
+        let pixels = match frame.color_type {
+            png::ColorType::Grayscale => buf.iter().map(|&g| [g, g, g, 255]).collect(),
+            png::ColorType::GrayscaleAlpha => {
+                buf.chunks(2).map(|x| [x[0], x[0], x[0], x[1]]).collect()
+            }
+            png::ColorType::Rgb => buf.chunks(3).map(|x| [x[0], x[1], x[2], 255]).collect(),
+            png::ColorType::Rgba => buf.chunks(4).map(|x| [x[0], x[1], x[2], x[3]]).collect(),
+            // expanded by the decoder
+            png::ColorType::Indexed => unreachable!(),
+        };
+
+        Ok((
+            Image {
+                width: frame.width,
+                height: frame.height,
+                pixels,
+            },
+            format,
+        ))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buf, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer
+            .write_image_data(self.pixels.concat().as_slice())
+            .unwrap();
+        writer.finish().unwrap();
+        buf
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+
+    /// A solid color image
+    pub fn fill(width: u32, height: u32, color: [u8; 4]) -> Result<Image, SizeError> {
+        Ok(Image {
+            width,
+            height,
+            pixels: vec![color; pixel_count(width as u64, height as u64)?],
+        })
+    }
+
+    /// A linear gradient from `from` to `to`, top to bottom or left to right
+    pub fn gradient(
+        width: u32,
+        height: u32,
+        from: [u8; 4],
+        to: [u8; 4],
+        vertical: bool,
+    ) -> Result<Image, SizeError> {
+        let mut result = Image::fill(width, height, from)?;
+        let steps = if vertical { height } else { width };
+        for y in 0..height {
+            for x in 0..width {
+                let i = if vertical { y } else { x };
+                let t = match steps {
+                    0 | 1 => 0.0,
+                    steps => i as f64 / (steps - 1) as f64,
+                };
+                let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+                let color = [
+                    mix(from[0], to[0]),
+                    mix(from[1], to[1]),
+                    mix(from[2], to[2]),
+                    mix(from[3], to[3]),
+                ];
+                result.set_pixel(x, y, color);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Apply a function to each pixel, leaving the pink pixel guides unchanged
+    fn map_pixels(&self, f: impl Fn([u8; 4]) -> [u8; 4]) -> Image {
+        let border = self.has_pink_border() as u32;
+        let mut result = self.clone();
+        for y in border..self.height {
+            for x in border..self.width {
+                result.set_pixel(x, y, f(self.pixel(x, y)));
+            }
+        }
+        result
+    }
+
+    /// Multiply each pixel by a color
+    pub fn tint(&self, color: [u8; 4]) -> Image {
+        let multiply = |a: u8, b: u8| ((a as u32 * b as u32 + 127) / 255) as u8;
+        self.map_pixels(|x| {
+            [
+                multiply(x[0], color[0]),
+                multiply(x[1], color[1]),
+                multiply(x[2], color[2]),
+                multiply(x[3], color[3]),
+            ]
+        })
+    }
+
+    /// Replace pixels of one color with another. A color without alpha matches pixels of any
+    /// alpha, and keeps the alpha of the pixels it replaces.
+    pub fn replace_color(&self, from: ([u8; 3], Option<u8>), to: ([u8; 3], Option<u8>)) -> Image {
+        self.map_pixels(|x| {
+            let matches = x[..3] == from.0 && from.1.is_none_or(|alpha| alpha == x[3]);
+            if matches {
+                let [r, g, b] = to.0;
+                [r, g, b, to.1.unwrap_or(x[3])]
+            } else {
+                x
+            }
+        })
+    }
+
+    /// Resize the image by a factor. The pink pixel guides stay 1px wide and are resized with
+    /// nearest neighbour sampling so they keep their exact colors; the rest of the image is resized
+    /// with bilinear sampling.
+    pub fn scale(&self, scale: f64) -> Result<Image, SizeError> {
+        let border = self.has_pink_border() as u32;
+        let (width, height) = (self.width - border, self.height - border);
+        let new_size = |size: u32| match size {
+            0 => 0,
+            size => ((size as f64 * scale).round() as u64).max(1),
+        };
+        let (new_width, new_height) = (new_size(width), new_size(height));
+        let pixels = pixel_count(
+            new_width.saturating_add(border as u64),
+            new_height.saturating_add(border as u64),
+        )?;
+        // both fit, since they are at most `MAX_SIZE`
+        let (new_width, new_height) = (new_width as u32, new_height as u32);
+
+        let mut result = Image {
+            width: new_width + border,
+            height: new_height + border,
+            pixels: vec![[0; 4]; pixels],
+        };
+
+        for y in 0..new_height {
+            let sy = source_coordinate(y, height, new_height);
+            for x in 0..new_width {
+                let sx = source_coordinate(x, width, new_width);
+                let color = self.sample(border, sx, sy);
+                result.set_pixel(x + border, y + border, color);
+            }
+        }
+
+        if border == 1 {
+            result.set_pixel(0, 0, self.pixel(0, 0));
+            for x in 0..new_width {
+                let sx = nearest(x, width, new_width);
+                result.set_pixel(x + 1, 0, self.pixel(sx + 1, 0));
+            }
+            for y in 0..new_height {
+                let sy = nearest(y, height, new_height);
+                result.set_pixel(0, y + 1, self.pixel(0, sy + 1));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Bilinear sample of the image excluding the border, using premultiplied alpha so transparent
+    /// pixels don't darken their neighbours
+    fn sample(&self, border: u32, x: f64, y: f64) -> [u8; 4] {
+        let (width, height) = (self.width - border, self.height - border);
+        let x = x.clamp(0.0, (width - 1) as f64);
+        let y = y.clamp(0.0, (height - 1) as f64);
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+
+        let mut sum = [0.0; 4];
+        for (px, py, weight) in [
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x1, y0, fx * (1.0 - fy)),
+            (x0, y1, (1.0 - fx) * fy),
+            (x1, y1, fx * fy),
+        ] {
+            let [r, g, b, a] = self.pixel(px + border, py + border);
+            let alpha = a as f64 / 255.0;
+            sum[0] += r as f64 * alpha * weight;
+            sum[1] += g as f64 * alpha * weight;
+            sum[2] += b as f64 * alpha * weight;
+            sum[3] += alpha * weight;
+        }
+
+        if sum[3] <= 0.0 {
+            return [0; 4];
+        }
+        let channel = |x: f64| x.round().clamp(0.0, 255.0) as u8;
+        [
+            channel(sum[0] / sum[3]),
+            channel(sum[1] / sum[3]),
+            channel(sum[2] / sum[3]),
+            channel(sum[3] * 255.0),
+        ]
+    }
+
+    /// Whether the image has REAPER's pink pixel guides in the top row and left column
+    pub fn has_pink_border(&self) -> bool {
+        self.width > 0 && self.height > 0 && self.pixel(0, 0) == PINK
+    }
+
+    pub fn shape(&self) -> Shape {
+        Shape {
+            width: self.width,
+            height: self.height,
+            pink_border: self.has_pink_border(),
+        }
+    }
+}
+
+/// The size of an image and whether it has pink pixel guides, enough to check a scaled copy
+/// without keeping both images decoded
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Shape {
+    pub width: u32,
+    pub height: u32,
+    pub pink_border: bool,
+}
+
+/// Losslessly recompress a PNG file: strip metadata, store images with at most 256 colors as an
+/// 8-bit palette, and re-deflate the image data. Grayscale and lower bit depths aren't used, so
+/// the result is always 8-bit RGB, RGBA or indexed. Returns `None` if the result isn't smaller.
+pub fn optimize(bytes: &[u8]) -> Result<Option<Vec<u8>>, oxipng::PngError> {
+    let mut options = oxipng::Options::from_preset(2);
+    options.strip = oxipng::StripChunks::Safe;
+    options.bit_depth_reduction = false;
+    options.grayscale_reduction = false;
+    let optimized = oxipng::optimize_from_memory(bytes, &options)?;
+    if optimized.len() < bytes.len() {
+        Ok(Some(optimized))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Position in the source image of the center of pixel `i` in the resized image
+fn source_coordinate(i: u32, size: u32, new_size: u32) -> f64 {
+    (i as f64 + 0.5) * size as f64 / new_size as f64 - 0.5
+}
+
+fn nearest(i: u32, size: u32, new_size: u32) -> u32 {
+    (((i as f64 + 0.5) * size as f64 / new_size as f64) as u32).min(size - 1)
+}
+
+/// Problems with the format of a PNG file that REAPER may not display correctly
+pub fn format_problems(format: &Format) -> Option<String> {
+    match (format.color_type, format.bit_depth) {
+        (
+            png::ColorType::Rgb | png::ColorType::Rgba | png::ColorType::Indexed,
+            png::BitDepth::Eight,
+        ) => None,
+        (color_type, bit_depth) => Some(format!(
+            "unexpected color type {:?} with bit depth {:?}; REAPER expects 8-bit RGB, RGBA or indexed",
+            color_type, bit_depth as u8
+        )),
+    }
+}
+
+/// Problems with the pink pixel guides of an image. The guides are only checked when the top left
+/// pixel is pink.
+pub fn border_problems(image: &Image) -> Vec<String> {
+    if !image.has_pink_border() {
+        return Vec::new();
+    }
+    if image.width < 3 || image.height < 3 {
+        return vec![format!(
+            "image with a pink border is {}x{}, it must be at least 3x3",
+            image.width, image.height
+        )];
+    }
+
+    let top = (1..image.width).map(|x| image.pixel(x, 0)).collect();
+    let left = (1..image.height).map(|y| image.pixel(0, y)).collect();
+    [("top row", top), ("left column", left)]
+        .into_iter()
+        .filter_map(|(name, pixels)| guide_problem(name, pixels))
+        .collect()
+}
+
+/// Check a row or column of guide pixels (excluding the corner). Guides may only be pink, yellow
+/// or transparent, and pink or yellow pixels must extend from either end.
+fn guide_problem(name: &str, pixels: Vec<[u8; 4]>) -> Option<String> {
+    let is_guide = |x: &[u8; 4]| *x == PINK || *x == YELLOW;
+
+    if let Some(i) = pixels.iter().position(|x| !is_guide(x) && x[3] != 0) {
+        let [r, g, b, a] = pixels[i];
+        return Some(format!(
+            "{name} of the pink border has a pixel that isn't pink, yellow or transparent at offset {}: rgba({r}, {g}, {b}, {a})",
+            i + 1
+        ));
+    }
+
+    // the corner counts as the start of the first margin
+    let start = 0;
+    let end = pixels.len();
+    let mut i = 0;
+    while i < end {
+        if !is_guide(&pixels[i]) {
+            i += 1;
+            continue;
+        }
+        let run_start = i;
+        while i < end && is_guide(&pixels[i]) {
+            i += 1;
+        }
+        // a run touching the corner is the start margin
+        if run_start != start && i != end {
+            return Some(format!(
+                "{name} of the pink border has guide pixels in the middle at offset {}; margins must start at the edges",
+                run_start + 1
+            ));
+        }
+    }
+
+    None
+}
+
+/// If `path` is inside a scale folder, the path of its base image and the scale
+pub fn scaled_base(path: &RelativePath) -> Option<(RelativePathBuf, f64)> {
+    let parent = path.parent()?;
+    let folder = parent.file_name()?;
+    let (_, scale) = SCALE_FOLDERS.iter().find(|(x, _)| *x == folder)?;
+    let base = parent.parent()?.join(path.file_name()?);
+    Some((base, *scale))
+}
+
+/// Generate scaled copies of `.png` resources in the `150` and `200` folders, for images that don't
+/// have one already
+pub fn generate_scaled_images(resources: &mut ResourceMap) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let mut bases: Vec<(RelativePathBuf, _)> = resources
+        .iter()
+        .filter(|(k, _)| {
+            k.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+                && scaled_base(k).is_none()
+        })
+        .map(|(k, v)| (k.normalize(), v.clone()))
+        .collect();
+    bases.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let existing: HashSet<RelativePathBuf> = resources.keys().map(|x| x.normalize()).collect();
+    for (archive_path, resource) in bases {
+        let missing: Vec<(RelativePathBuf, f64)> = SCALE_FOLDERS
+            .iter()
+            .map(|(folder, scale)| {
+                let parent = archive_path.parent().unwrap_or(RelativePath::new(""));
+                (
+                    parent.join(folder).join(archive_path.file_name().unwrap()),
+                    *scale,
+                )
+            })
+            .filter(|(path, _)| !existing.contains(path))
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+
+        let image = match resource
+            .read()
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| Image::decode(&bytes).map_err(|err| err.to_string()))
+        {
+            Ok((image, _)) => image,
+            Err(err) => {
+                diagnostics.push(
+                    Diagnostic::warning(
+                        "hidpi-decode",
+                        format!("failed to read image for generating scaled copies: {err}"),
+                    )
+                    .file(
+                        &resource
+                            .path()
+                            .map_or(archive_path.to_path(""), Path::to_path_buf),
+                    ),
+                );
+                continue;
+            }
+        };
+
+        for (path, scale) in missing {
+            let scaled = match image.scale(scale) {
+                Ok(scaled) => scaled,
+                Err(err) => {
+                    diagnostics.push(
+                        Diagnostic::warning(
+                            "hidpi-size",
+                            format!("failed to generate `{path}`: {err}"),
+                        )
+                        .file(&archive_path.to_path("")),
+                    );
+                    continue;
+                }
+            };
+            debug!("generated `{}` from `{}`", path, archive_path);
+            resources.insert(path, Resource::Generated(scaled.encode()));
+        }
+    }
+
+    diagnostics
+}
+
+/// Check that a scaled image's size matches its base image, accounting for the pink border
+pub fn scale_problem(base: &Shape, scaled: &Shape, scale: f64) -> Option<String> {
+    if base.pink_border != scaled.pink_border {
+        return Some(if base.pink_border {
+            "base image has a pink border, but the scaled image doesn't".to_string()
+        } else {
+            "scaled image has a pink border, but the base image doesn't".to_string()
+        });
+    }
+
+    let border = base.pink_border as u32;
+    let expected = |size: u32| ((size - border) as f64 * scale).round() as u32 + border;
+    let (width, height) = (expected(base.width), expected(base.height));
+    // allow rounding to either side
+    if width.abs_diff(scaled.width) > 1 || height.abs_diff(scaled.height) > 1 {
+        return Some(format!(
+            "scaled image is {}x{}, expected {}x{} ({}x of the {}x{} base image)",
+            scaled.width, scaled.height, width, height, scale, base.width, base.height
+        ));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+
+    fn image(width: u32, height: u32, pixels: &[[u8; 4]]) -> Image {
+        Image {
+            width,
+            height,
+            pixels: pixels.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let pixels = [PINK, CLEAR, RED, YELLOW];
+        let (decoded, format) = Image::decode(&image(2, 2, &pixels).encode()).unwrap();
+        assert_eq!(decoded, image(2, 2, &pixels));
+        assert_eq!(format_problems(&format), None);
+
+        assert!(Image::decode(b"not a png").is_err());
+        let tall = image(1, MAX_SIZE + 1, &vec![RED; MAX_SIZE as usize + 1]).encode();
+        assert!(matches!(Image::decode(&tall), Err(DecodeError::Size(_))));
+
+        let grey = Format {
+            color_type: png::ColorType::Grayscale,
+            bit_depth: png::BitDepth::Sixteen,
+        };
+        assert_eq!(
+            format_problems(&grey).unwrap(),
+            "unexpected color type Grayscale with bit depth 16; REAPER expects 8-bit RGB, RGBA or indexed"
+        );
+    }
+
+    #[test]
+    fn test_border_problems() {
+        #[rustfmt::skip]
+        let valid = image(5, 4, &[
+            PINK, PINK, CLEAR, PINK, PINK,
+            PINK, RED,  RED,   RED,  RED,
+            CLEAR, RED, RED,   RED,  RED,
+            YELLOW, RED, RED,  RED,  RED,
+        ]);
+        assert_eq!(border_problems(&valid), Vec::<String>::new());
+
+        // not a pink border image
+        assert_eq!(border_problems(&image(1, 1, &[RED])), Vec::<String>::new());
+
+        assert_eq!(
+            border_problems(&image(2, 1, &[PINK, PINK])),
+            vec!["image with a pink border is 2x1, it must be at least 3x3"]
+        );
+
+        #[rustfmt::skip]
+        let invalid = image(5, 4, &[
+            PINK, CLEAR, PINK, CLEAR, CLEAR,
+            RED,  RED,   RED,  RED,   RED,
+            CLEAR, RED,  RED,  RED,   RED,
+            CLEAR, RED,  RED,  RED,   RED,
+        ]);
+        assert_eq!(
+            border_problems(&invalid),
+            vec![
+                "top row of the pink border has guide pixels in the middle at offset 2; margins must start at the edges",
+                "left column of the pink border has a pixel that isn't pink, yellow or transparent at offset 1: rgba(255, 0, 0, 255)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scale_problem() {
+        assert_eq!(
+            scaled_base(RelativePath::new("layout/200/knob.png")),
+            Some((RelativePathBuf::from("layout/knob.png"), 2.0))
+        );
+        assert_eq!(
+            scaled_base(RelativePath::new("150/knob.png")),
+            Some((RelativePathBuf::from("knob.png"), 1.5))
+        );
+        assert_eq!(scaled_base(RelativePath::new("knob.png")), None);
+
+        let base = image(10, 4, &[RED; 40]);
+        assert_eq!(
+            scale_problem(&base.shape(), &image(15, 6, &[RED; 90]).shape(), 1.5),
+            None
+        );
+        assert_eq!(
+            scale_problem(&base.shape(), &image(10, 4, &[RED; 40]).shape(), 2.0).unwrap(),
+            "scaled image is 10x4, expected 20x8 (2x of the 10x4 base image)"
+        );
+
+        // the border stays 1px wide
+        let mut base = image(5, 3, &[RED; 15]);
+        base.pixels[0] = PINK;
+        let mut scaled = image(9, 5, &[RED; 45]);
+        scaled.pixels[0] = PINK;
+        assert_eq!(scale_problem(&base.shape(), &scaled.shape(), 2.0), None);
+        assert_eq!(
+            scale_problem(&base.shape(), &image(9, 5, &[RED; 45]).shape(), 2.0).unwrap(),
+            "base image has a pink border, but the scaled image doesn't"
+        );
+    }
+
+    #[test]
+    fn test_scale() {
+        const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+        let plain = image(2, 1, &[RED, BLUE]);
+        let scaled = plain.scale(2.0).unwrap();
+        assert_eq!((scaled.width, scaled.height), (4, 2));
+        assert_eq!(scaled.pixel(0, 0), RED);
+        assert_eq!(scaled.pixel(1, 0), [191, 0, 64, 255]);
+        assert_eq!(scaled.pixel(3, 1), BLUE);
+
+        // transparent pixels don't bleed their color
+        let scaled = image(2, 1, &[RED, CLEAR]).scale(2.0).unwrap();
+        assert_eq!(scaled.pixel(1, 0), [255, 0, 0, 191]);
+
+        #[rustfmt::skip]
+        let bordered = image(4, 3, &[
+            PINK,  PINK, CLEAR, PINK,
+            PINK,  RED,  RED,   RED,
+            CLEAR, BLUE, BLUE,  BLUE,
+        ]);
+        let scaled = bordered.scale(2.0).unwrap();
+        assert_eq!((scaled.width, scaled.height), (7, 5));
+        let top: Vec<_> = (0..7).map(|x| scaled.pixel(x, 0)).collect();
+        assert_eq!(top, [PINK, PINK, PINK, CLEAR, CLEAR, PINK, PINK]);
+        let left: Vec<_> = (0..5).map(|y| scaled.pixel(0, y)).collect();
+        assert_eq!(left, [PINK, PINK, PINK, CLEAR, CLEAR]);
+        assert_eq!(scaled.pixel(1, 1), RED);
+        assert_eq!(scaled.pixel(1, 4), BLUE);
+        assert_eq!(border_problems(&scaled), Vec::<String>::new());
+        assert_eq!(scale_problem(&bordered.shape(), &scaled.shape(), 2.0), None);
+
+        let scaled = bordered.scale(1.5).unwrap();
+        assert_eq!((scaled.width, scaled.height), (6, 4));
+        assert_eq!(scale_problem(&bordered.shape(), &scaled.shape(), 1.5), None);
+    }
+
+    #[test]
+    fn test_image_operations() {
+        let gradient = Image::gradient(3, 1, [0, 0, 0, 255], [255, 100, 0, 255], false).unwrap();
+        assert_eq!(
+            gradient.pixels,
+            [[0, 0, 0, 255], [128, 50, 0, 255], [255, 100, 0, 255]]
+        );
+        let gradient = Image::gradient(1, 2, RED, CLEAR, true).unwrap();
+        assert_eq!(gradient.pixels, [RED, CLEAR]);
+
+        // sizes are limited
+        assert!(Image::fill(MAX_SIZE, 1, RED).is_ok());
+        assert!(Image::fill(MAX_SIZE + 1, 1, RED).is_err());
+        assert!(Image::fill(4096, 4096, RED).is_ok());
+        assert!(Image::fill(4096, 4097, RED).is_err());
+        assert!(Image::fill(MAX_SIZE, MAX_SIZE, RED).is_err());
+        assert!(Image::gradient(u32::MAX, u32::MAX, RED, CLEAR, true).is_err());
+        assert!(gradient.scale(1e12).is_err());
+        assert!(gradient.scale(f64::INFINITY).is_err());
+
+        #[rustfmt::skip]
+        let bordered = image(3, 3, &[
+            PINK, PINK,  PINK,
+            PINK, RED,   RED,
+            PINK, CLEAR, [255, 0, 0, 128],
+        ]);
+
+        let tinted = bordered.tint([128, 255, 255, 255]);
+        assert_eq!(tinted.pixel(0, 0), PINK);
+        assert_eq!(tinted.pixel(1, 0), PINK);
+        assert_eq!(tinted.pixel(1, 1), [128, 0, 0, 255]);
+        assert_eq!(tinted.pixel(2, 2), [128, 0, 0, 128]);
+
+        // RGB colors match any alpha and keep it
+        let replaced = bordered.replace_color(([255, 0, 0], None), ([0, 0, 255], None));
+        assert_eq!(replaced.pixel(0, 1), PINK);
+        assert_eq!(replaced.pixel(1, 1), [0, 0, 255, 255]);
+        assert_eq!(replaced.pixel(2, 2), [0, 0, 255, 128]);
+
+        let replaced = bordered.replace_color(([255, 0, 0], Some(128)), ([0, 0, 255], Some(255)));
+        assert_eq!(replaced.pixel(1, 1), RED);
+        assert_eq!(replaced.pixel(2, 2), [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_optimize() {
+        // an uncompressed image with metadata and a few colors
+        let colors = [RED, CLEAR, PINK, YELLOW, [0, 0, 255, 128]];
+        let pixels: Vec<[u8; 4]> = (0..32 * 32u32)
+            .map(|i| colors[(i.wrapping_mul(2654435761) >> 29) as usize % 5])
+            .collect();
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 32, 32);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_compression(png::Compression::Fast);
+            encoder
+                .add_text_chunk("Comment".into(), "made in an image editor".into())
+                .unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&pixels.concat()).unwrap();
+        }
+
+        let optimized = optimize(&bytes).unwrap().unwrap();
+        assert!(optimized.len() < bytes.len());
+        let (decoded, format) = Image::decode(&optimized).unwrap();
+        assert_eq!(decoded, image(32, 32, &pixels));
+        // few colors are stored as a palette, but not with a lower bit depth
+        assert_eq!(format.color_type, png::ColorType::Indexed);
+        assert_eq!(format.bit_depth, png::BitDepth::Eight);
+        assert_eq!(format_problems(&format), None);
+
+        // optimizing again may find a better palette order, but never changes the pixels
+        if let Some(again) = optimize(&optimized).unwrap() {
+            assert!(again.len() < optimized.len());
+            assert_eq!(Image::decode(&again).unwrap().0, decoded);
+        }
+
+        assert!(optimize(b"not a png").is_err());
+    }
+
+    #[test]
+    fn test_generate_scaled_images() {
+        let dir = TempDir::new("hidpi");
+        let source = dir.join("knob.png");
+        std::fs::write(&source, image(2, 2, &[RED; 4]).encode()).unwrap();
+        let hand_drawn = dir.join("knob_200.png");
+        std::fs::write(&hand_drawn, image(4, 4, &[[0, 0, 255, 255]; 16]).encode()).unwrap();
+
+        let mut resources = ResourceMap::new();
+        resources.insert("a/knob.png".into(), Resource::File(source.clone()));
+        resources.insert("a/200/knob.png".into(), Resource::File(hand_drawn.clone()));
+        resources.insert("notes.txt".into(), Resource::File(dir.join("notes.txt")));
+
+        assert!(generate_scaled_images(&mut resources).is_empty());
+
+        let mut keys: Vec<String> = resources.keys().map(|x| x.to_string()).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "a/150/knob.png",
+                "a/200/knob.png",
+                "a/knob.png",
+                "notes.txt"
+            ]
+        );
+        assert_eq!(
+            resources[RelativePath::new("a/200/knob.png")],
+            Resource::File(hand_drawn)
+        );
+
+        let Resource::Generated(generated) = &resources[RelativePath::new("a/150/knob.png")] else {
+            panic!("scaled image should be generated in memory");
+        };
+        let (image, _) = Image::decode(generated).unwrap();
+        assert_eq!((image.width, image.height), (3, 3));
+    }
+}
@@ -1,18 +1,88 @@
 use std::{
     collections::HashSet,
-    fmt::{LowerHex, Pointer, UpperHex},
-    sync::{Arc, LazyLock, Mutex},
+    fmt::{Display, LowerHex, UpperHex},
+    path::PathBuf,
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use mlua::{FromLua, IntoLua};
+use clap::ValueEnum;
+use mlua::{FromLua, IntoLua, LuaSerdeExt};
 use relative_path::RelativePathBuf;
 use thiserror::Error;
 
-use crate::parser::{Directive, ParseError};
+use crate::{
+    cache,
+    images::Image,
+    palette::{self, ColorSpace},
+    parser::{placeholders, resource_pattern, ErrorLocation, ResourceDirective},
+    theme::Resource,
+};
+
+/// A resource generated in Lua code and its destination in the theme
+pub(crate) type GeneratedResource = (RelativePathBuf, Resource);
+
+/// Resources generated in Lua code, e.g. `resource("knob.png", image)`, stored as Lua app data
+/// until they are taken with [`take_generated_resources`]
+#[derive(Default)]
+struct GeneratedResources(Vec<GeneratedResource>);
+
+fn push_generated_resource(lua: &mlua::Lua, dest: RelativePathBuf, resource: Resource) {
+    match lua.app_data_mut::<GeneratedResources>() {
+        Some(mut generated) => generated.0.push((dest, resource)),
+        None => {
+            lua.set_app_data(GeneratedResources(vec![(dest, resource)]));
+        }
+    }
+}
+
+/// Take the resources generated by Lua code since the last call
+pub fn take_generated_resources(lua: &mlua::Lua) -> Vec<GeneratedResource> {
+    lua.remove_app_data::<GeneratedResources>()
+        .map(|x| x.0)
+        .unwrap_or_default()
+}
+
+/// Calls to `resource()` in Lua code, stored as Lua app data until they are taken with
+/// [`take_resource_directives`]
+#[derive(Default)]
+struct ResourceDirectives(Vec<ResourceDirective>);
+
+fn push_resource_directive(lua: &mlua::Lua, directive: ResourceDirective) {
+    match lua.app_data_mut::<ResourceDirectives>() {
+        Some(mut directives) => directives.0.push(directive),
+        None => {
+            lua.set_app_data(ResourceDirectives(vec![directive]));
+        }
+    }
+}
+
+/// Take the calls to `resource()` made by Lua code since the last call
+pub fn take_resource_directives(lua: &mlua::Lua) -> Vec<ResourceDirective> {
+    lua.remove_app_data::<ResourceDirectives>()
+        .map(|x| x.0)
+        .unwrap_or_default()
+}
+
+/// The file currently being processed, stored as Lua app data. Lua functions that read files
+/// resolve paths relative to its folder.
+pub struct SourcePath(pub PathBuf);
 
-// this is to allow adding resources from Lua code, i have no idea what other way to do this
-pub(crate) static NEW_RESOURCE_PATHS: LazyLock<Mutex<Vec<Directive>>> =
-    LazyLock::new(|| Mutex::new(Vec::new()));
+/// The root folder of the theme sources, stored as Lua app data. `require` only loads modules
+/// inside it.
+pub struct ProjectRoot(pub PathBuf);
+
+#[derive(Error, Debug)]
+enum ModuleError {
+    #[error("cannot require module `{0}` outside the root folder")]
+    OutsideRoot(String),
+    #[error("module `{0}` not found, tried {1}")]
+    NotFound(String, String),
+    #[error("module `{0}` is required again while it is being loaded")]
+    Circular(String),
+}
 
 #[derive(Error, Debug)]
 enum ColorError {
@@ -20,14 +90,46 @@ enum ColorError {
     ValueOutOfBounds(u32, u8),
     #[error("invalid channel count `{0}`")]
     InvalidChannels(u8),
-    #[error("cannot apply negative() to RGBA color")]
-    NegativeRGBA,
+    #[error("value `{0}` is not a toggleable color")]
+    InvalidToggle(i64),
     #[error("cannot perform arithmetic on two colors with different channels")]
     ArithmeticChannelsMismatch,
     #[error("color addition caused one of the channels to overflow past 255")]
     ArithmeticOverflow,
     #[error("color subtraction caused one of the channels to underflow below 0")]
     ArithmeticUnderflow,
+    #[error("color multiplication caused one of the channels to go outside 0 to 255")]
+    ScaleOutOfRange,
+    #[error("cannot divide a color by zero")]
+    DivisionByZero,
+    #[error("cannot compare colors with different channels")]
+    CompareChannelsMismatch,
+}
+
+/// Multiply a channel by a number, rounding to the nearest value
+fn scale_channel(value: u8, factor: f64) -> Result<u8, ColorError> {
+    let result = (value as f64 * factor).round();
+    if (0.0..=255.0).contains(&result) {
+        Ok(result as u8)
+    } else {
+        Err(ColorError::ScaleOutOfRange)
+    }
+}
+
+/// Read the operands of a color arithmetic metamethod, where the number may be on either side
+fn color_and_number<T: FromLua>(
+    lua: &mlua::Lua,
+    (a, b): (mlua::Value, mlua::Value),
+) -> mlua::Result<(T, f64)> {
+    match a {
+        mlua::Value::UserData(_) => Ok((T::from_lua(a, lua)?, f64::from_lua(b, lua)?)),
+        _ => Ok((T::from_lua(b, lua)?, f64::from_lua(a, lua)?)),
+    }
+}
+
+/// Concatenate two values as strings, using `__tostring` for colors
+fn concat(a: mlua::Value, b: mlua::Value) -> mlua::Result<String> {
+    Ok(a.to_string()? + &b.to_string()?)
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, FromLua)]
@@ -86,6 +188,15 @@ impl RGB {
         ))
     }
 
+    /// Multiply the channels by a number
+    fn scale(&self, factor: f64) -> Result<Self, ColorError> {
+        Ok(Self(
+            scale_channel(self.0, factor)?,
+            scale_channel(self.1, factor)?,
+            scale_channel(self.2, factor)?,
+        ))
+    }
+
     /// Subtract 0x1000000 from the reversed value. Used in *.ReaperTheme when a color has a togglable
     /// option, e.g. `col_main_bg` and `col_seltrack2`
     fn negative(&self) -> i64 {
@@ -168,9 +279,24 @@ impl RGBA {
         ))
     }
 
+    /// Multiply the color channels by a number, keeping the alpha
+    fn scale(&self, factor: f64) -> Result<Self, ColorError> {
+        Ok(Self(
+            scale_channel(self.0, factor)?,
+            scale_channel(self.1, factor)?,
+            scale_channel(self.2, factor)?,
+            self.3,
+        ))
+    }
+
     fn to_rgb(&self) -> RGB {
         RGB(self.0, self.1, self.2)
     }
+
+    /// Subtract 0x100000000 from the reversed value, the RGBA version of [`RGB::negative`]
+    fn negative(&self) -> i64 {
+        self.value_rev() as i64 - 0x100000000
+    }
 }
 
 impl UpperHex for RGBA {
@@ -194,6 +320,12 @@ impl LowerHex for RGBA {
 }
 
 impl mlua::UserData for RGB {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("r", |_, this| Ok(this.0));
+        fields.add_field_method_get("g", |_, this| Ok(this.1));
+        fields.add_field_method_get("b", |_, this| Ok(this.2));
+    }
+
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
         // methods
         methods.add_method("arr", |_, this, _value: ()| Ok(this.arr()));
@@ -212,10 +344,51 @@ impl mlua::UserData for RGB {
             this.sub(&other)
                 .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
         });
+        methods.add_meta_function(mlua::MetaMethod::Mul, |lua, args| {
+            let (color, factor) = color_and_number::<RGB>(lua, args)?;
+            color
+                .scale(factor)
+                .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+        });
+        methods.add_meta_method(mlua::MetaMethod::Div, |_, this, divisor: f64| {
+            if divisor == 0.0 {
+                return Err(mlua::Error::ExternalError(Arc::new(
+                    ColorError::DivisionByZero,
+                )));
+            }
+            this.scale(1.0 / divisor)
+                .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+        });
+        methods.add_meta_method(mlua::MetaMethod::Eq, |_, this, other: mlua::AnyUserData| {
+            Ok(other.borrow::<RGB>().is_ok_and(|other| *this == *other))
+        });
+        methods.add_meta_method(mlua::MetaMethod::Lt, |_, this, other: mlua::AnyUserData| {
+            let other = other.borrow::<RGB>().map_err(|_| {
+                mlua::Error::ExternalError(Arc::new(ColorError::CompareChannelsMismatch))
+            })?;
+            Ok(this.value() < other.value())
+        });
+        methods.add_meta_method(mlua::MetaMethod::Le, |_, this, other: mlua::AnyUserData| {
+            let other = other.borrow::<RGB>().map_err(|_| {
+                mlua::Error::ExternalError(Arc::new(ColorError::CompareChannelsMismatch))
+            })?;
+            Ok(this.value() <= other.value())
+        });
+        methods.add_meta_method(mlua::MetaMethod::ToString, |_, this, _value: ()| {
+            Ok(format!("rgb({}, {}, {})", this.0, this.1, this.2))
+        });
+        methods.add_meta_function(mlua::MetaMethod::Concat, |_, (a, b)| concat(a, b));
     }
 }
 
 impl mlua::UserData for RGBA {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("r", |_, this| Ok(this.0));
+        fields.add_field_method_get("g", |_, this| Ok(this.1));
+        fields.add_field_method_get("b", |_, this| Ok(this.2));
+        fields.add_field_method_get("a", |_, this| Ok(this.3));
+    }
+
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
         // methods
         methods.add_method("arr", |_, this, _value: ()| Ok(this.arr()));
@@ -223,6 +396,7 @@ impl mlua::UserData for RGBA {
             Ok(this.with_alpha(alpha))
         });
         methods.add_method("to_rgb", |_, this, _value: ()| Ok(this.to_rgb()));
+        methods.add_method("negative", |_, this, _value: ()| Ok(this.negative()));
         methods.add_method("hex", |_, this, _value: ()| Ok(format!("{:X}", this)));
 
         // metamethods
@@ -234,15 +408,77 @@ impl mlua::UserData for RGBA {
             this.sub(&other)
                 .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
         });
+        methods.add_meta_function(mlua::MetaMethod::Mul, |lua, args| {
+            let (color, factor) = color_and_number::<RGBA>(lua, args)?;
+            color
+                .scale(factor)
+                .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+        });
+        methods.add_meta_method(mlua::MetaMethod::Div, |_, this, divisor: f64| {
+            if divisor == 0.0 {
+                return Err(mlua::Error::ExternalError(Arc::new(
+                    ColorError::DivisionByZero,
+                )));
+            }
+            this.scale(1.0 / divisor)
+                .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+        });
+        methods.add_meta_method(mlua::MetaMethod::Eq, |_, this, other: mlua::AnyUserData| {
+            Ok(other.borrow::<RGBA>().is_ok_and(|other| *this == *other))
+        });
+        methods.add_meta_method(mlua::MetaMethod::Lt, |_, this, other: mlua::AnyUserData| {
+            let other = other.borrow::<RGBA>().map_err(|_| {
+                mlua::Error::ExternalError(Arc::new(ColorError::CompareChannelsMismatch))
+            })?;
+            Ok(this.value() < other.value())
+        });
+        methods.add_meta_method(mlua::MetaMethod::Le, |_, this, other: mlua::AnyUserData| {
+            let other = other.borrow::<RGBA>().map_err(|_| {
+                mlua::Error::ExternalError(Arc::new(ColorError::CompareChannelsMismatch))
+            })?;
+            Ok(this.value() <= other.value())
+        });
+        methods.add_meta_method(mlua::MetaMethod::ToString, |_, this, _value: ()| {
+            Ok(format!(
+                "rgba({}, {}, {}, {})",
+                this.0, this.1, this.2, this.3
+            ))
+        });
+        methods.add_meta_function(mlua::MetaMethod::Concat, |_, (a, b)| concat(a, b));
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Color {
     RGB(RGB),
     RGBA(RGBA),
 }
 
 impl Color {
+    /// Read a reversed value from *.ReaperTheme, an RGBA color if it doesn't fit in 3 channels
+    fn from_value_rev(value: u32) -> Self {
+        let [r, g, b, a] = value.to_le_bytes();
+        if value <= 0xffffff {
+            Self::RGB(RGB(r, g, b))
+        } else {
+            Self::RGBA(RGBA(r, g, b, a))
+        }
+    }
+
+    fn value_rev(&self) -> u32 {
+        match self {
+            Self::RGB(x) => x.value_rev(),
+            Self::RGBA(x) => x.value_rev(),
+        }
+    }
+
+    fn negative(&self) -> i64 {
+        match self {
+            Self::RGB(x) => x.negative(),
+            Self::RGBA(x) => x.negative(),
+        }
+    }
+
     fn from_value(value: u32) -> Result<Self, ColorError> {
         if value <= 0xffffff {
             Self::new_with_channels(value, 3)
@@ -285,6 +521,1034 @@ impl IntoLua for Color {
     }
 }
 
+#[derive(Error, Debug)]
+pub enum BlendError {
+    #[error("frac `{0}` must be a value between 0.0 and 1.0")]
+    FracOutOfRange(f64),
+    #[error(
+        "mode `{0}` must be one of: {}, or a number from 0 to 255",
+        "\"normal\", \"add\", \"overlay\", \"multiply\", \"dodge\", \"hsv\""
+    )]
+    UnknownMode(String),
+}
+
+/// Blend mode of a blend value, the lowest byte of the value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,
+    Add,
+    Dodge,
+    Multiply,
+    Overlay,
+    Hsv,
+    /// a mode without a name, only given as a number. Kept so values from existing themes
+    /// round-trip
+    Other(u8),
+}
+
+impl BlendMode {
+    fn from_byte(x: u8) -> Self {
+        match x {
+            0 => Self::Normal,
+            1 => Self::Add,
+            2 => Self::Dodge,
+            3 => Self::Multiply,
+            4 => Self::Overlay,
+            0xfe => Self::Hsv,
+            x => Self::Other(x),
+        }
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            Self::Normal => 0,
+            Self::Add => 1,
+            Self::Dodge => 2,
+            Self::Multiply => 3,
+            Self::Overlay => 4,
+            Self::Hsv => 0xfe,
+            Self::Other(x) => x,
+        }
+    }
+
+    fn name(self) -> Option<&'static str> {
+        match self {
+            Self::Normal => Some("normal"),
+            Self::Add => Some("add"),
+            Self::Dodge => Some("dodge"),
+            Self::Multiply => Some("multiply"),
+            Self::Overlay => Some("overlay"),
+            Self::Hsv => Some("hsv"),
+            Self::Other(_) => None,
+        }
+    }
+}
+
+impl FromStr for BlendMode {
+    type Err = BlendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(Self::Normal),
+            "add" => Ok(Self::Add),
+            "dodge" => Ok(Self::Dodge),
+            "multiply" => Ok(Self::Multiply),
+            "overlay" => Ok(Self::Overlay),
+            "hsv" => Ok(Self::Hsv),
+            x => Err(BlendError::UnknownMode(x.to_string())),
+        }
+    }
+}
+
+/// A blend value used in *.ReaperTheme, e.g. `midi_itemctl_mode`
+#[derive(Debug, Clone, PartialEq, Eq, FromLua)]
+pub enum Blend {
+    /// An 18-bit value, split into multiple parts:
+    ///
+    ///     0b1 frac_____ mode____
+    ///     0b1 100000000 11111110
+    ///
+    /// frac is a fraction: x / 256
+    Mode { mode: BlendMode, frac: u16 },
+    /// A value that isn't an encoded mode, e.g. `-1` in some themes, kept as it is
+    Raw(i64),
+}
+
+impl Blend {
+    fn new(mode: BlendMode, frac: f64) -> Result<Self, BlendError> {
+        if !(0.0..=1.0).contains(&frac) {
+            return Err(BlendError::FracOutOfRange(frac));
+        }
+        // find the nearest x / 256 value
+        let frac = (frac * 256.0).round() as u16;
+        Ok(Self::Mode { mode, frac })
+    }
+
+    /// Decode a value from a *.ReaperTheme file. Values that aren't encoded modes are kept as raw
+    /// values, so decoding never fails
+    pub fn decode(value: i64) -> Self {
+        let frac = (value >> 8) & 0x1ff;
+        if value >> 17 == 1 && frac <= 256 {
+            Self::Mode {
+                mode: BlendMode::from_byte((value & 0xff) as u8),
+                frac: frac as u16,
+            }
+        } else {
+            Self::Raw(value)
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        match self {
+            Self::Mode { mode, frac } => (1 << 17) + ((*frac as i64) << 8) + mode.byte() as i64,
+            Self::Raw(x) => *x,
+        }
+    }
+}
+
+impl Display for Blend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mode { mode, frac } => {
+                let frac = *frac as f64 / 256.0;
+                match mode.name() {
+                    Some(name) => write!(f, "blend(\"{}\", {})", name, frac),
+                    None => write!(f, "blend({}, {})", mode.byte(), frac),
+                }
+            }
+            Self::Raw(x) => write!(f, "decode_blend({})", x),
+        }
+    }
+}
+
+impl mlua::UserData for Blend {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("mode", |lua, this| match this {
+            Blend::Mode { mode, .. } => match mode.name() {
+                Some(name) => name.into_lua(lua),
+                None => mode.byte().into_lua(lua),
+            },
+            Blend::Raw(_) => Ok(mlua::Value::Nil),
+        });
+        fields.add_field_method_get("frac", |_, this| match this {
+            Blend::Mode { frac, .. } => Ok(Some(*frac as f64 / 256.0)),
+            Blend::Raw(_) => Ok(None),
+        });
+        fields.add_field_method_get("value", |_, this| Ok(this.value()));
+    }
+
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        // blend values were plain numbers before, so they still work in arithmetic and
+        // comparisons with numbers
+        methods.add_meta_function(mlua::MetaMethod::Add, |_, (a, b)| {
+            blend_arith(a, b, i64::wrapping_add, |a, b| a + b)
+        });
+        methods.add_meta_function(mlua::MetaMethod::Sub, |_, (a, b)| {
+            blend_arith(a, b, i64::wrapping_sub, |a, b| a - b)
+        });
+        methods.add_meta_function(mlua::MetaMethod::Lt, |_, (a, b): (mlua::Value, _)| {
+            Ok(blend_number(&a)? < blend_number(&b)?)
+        });
+        methods.add_meta_function(mlua::MetaMethod::Le, |_, (a, b): (mlua::Value, _)| {
+            Ok(blend_number(&a)? <= blend_number(&b)?)
+        });
+        methods.add_meta_method(mlua::MetaMethod::Eq, |_, this, other: mlua::AnyUserData| {
+            Ok(other.borrow::<Blend>().is_ok_and(|other| *this == *other))
+        });
+        methods.add_meta_method(mlua::MetaMethod::ToString, |_, this, _value: ()| {
+            Ok(this.to_string())
+        });
+        methods.add_meta_function(mlua::MetaMethod::Concat, |_, (a, b)| concat(a, b));
+    }
+}
+
+/// The value of a blend value, or a number as it is
+fn blend_operand(value: &mlua::Value) -> mlua::Result<mlua::Value> {
+    match value {
+        mlua::Value::UserData(x) if x.is::<Blend>() => {
+            Ok(mlua::Value::Integer(x.borrow::<Blend>()?.value()))
+        }
+        mlua::Value::Integer(_) | mlua::Value::Number(_) => Ok(value.clone()),
+        x => Err(mlua::Error::runtime(format!(
+            "cannot use a blend value with a {}",
+            x.type_name()
+        ))),
+    }
+}
+
+fn blend_number(value: &mlua::Value) -> mlua::Result<f64> {
+    match blend_operand(value)? {
+        mlua::Value::Integer(x) => Ok(x as f64),
+        x => Ok(x.as_f64().unwrap_or_default()),
+    }
+}
+
+/// Add or subtract blend values and numbers; like in Lua, the result is an integer if both are
+fn blend_arith(
+    a: mlua::Value,
+    b: mlua::Value,
+    integer: fn(i64, i64) -> i64,
+    float: fn(f64, f64) -> f64,
+) -> mlua::Result<mlua::Value> {
+    match (blend_operand(&a)?, blend_operand(&b)?) {
+        (mlua::Value::Integer(a), mlua::Value::Integer(b)) => {
+            Ok(mlua::Value::Integer(integer(a, b)))
+        }
+        _ => Ok(mlua::Value::Number(float(
+            blend_number(&a)?,
+            blend_number(&b)?,
+        ))),
+    }
+}
+
+/// A color with an option to turn it off, e.g. `col_main_bg` and `col_seltrack2`. Turned off
+/// colors are serialised with [`RGB::negative`] or [`RGBA::negative`]
+#[derive(Debug, Clone, PartialEq, Eq, FromLua)]
+pub struct Toggle {
+    color: Color,
+    enabled: bool,
+}
+
+impl Toggle {
+    /// Decode a value from a *.ReaperTheme file, negative values are turned off colors. Turned on
+    /// RGBA colors with an alpha of 0 and turned off ones with an alpha of 255 have the same values
+    /// as RGB colors, and are decoded as RGB colors
+    fn decode(value: i64) -> Result<Self, ColorError> {
+        let (color, enabled) = match value {
+            0..=0xffffffff => (Color::from_value_rev(value as u32), true),
+            -0x1000000..=-1 => (Color::from_value_rev((value + 0x1000000) as u32), false),
+            -0xff000000..=-0x1000001 => {
+                (Color::from_value_rev((value + 0x100000000) as u32), false)
+            }
+            // turned off RGBA colors with an alpha of 0
+            -0x100000000..=-0xff000001 => {
+                let [r, g, b, _] = ((value + 0x100000000) as u32).to_le_bytes();
+                (Color::RGBA(RGBA(r, g, b, 0)), false)
+            }
+            x => return Err(ColorError::InvalidToggle(x)),
+        };
+        Ok(Self { color, enabled })
+    }
+
+    pub fn value(&self) -> i64 {
+        if self.enabled {
+            self.color.value_rev() as i64
+        } else {
+            self.color.negative()
+        }
+    }
+}
+
+impl Display for Toggle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.color {
+            Color::RGB(x) => write!(
+                f,
+                "toggle(rgb({}, {}, {}), {})",
+                x.0, x.1, x.2, self.enabled
+            ),
+            Color::RGBA(x) => write!(
+                f,
+                "toggle(rgba({}, {}, {}, {}), {})",
+                x.0, x.1, x.2, x.3, self.enabled
+            ),
+        }
+    }
+}
+
+impl mlua::UserData for Toggle {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("color", |_, this| Ok(this.color.clone()));
+        fields.add_field_method_get("enabled", |_, this| Ok(this.enabled));
+        fields.add_field_method_get("value", |_, this| Ok(this.value()));
+    }
+
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(mlua::MetaMethod::Eq, |_, this, other: mlua::AnyUserData| {
+            Ok(other.borrow::<Toggle>().is_ok_and(|other| *this == *other))
+        });
+        methods.add_meta_method(mlua::MetaMethod::ToString, |_, this, _value: ()| {
+            Ok(this.to_string())
+        });
+        methods.add_meta_function(mlua::MetaMethod::Concat, |_, (a, b)| concat(a, b));
+    }
+}
+
+/// Read a color argument, returning `None` as the alpha of RGB colors
+fn color_arg(value: &mlua::Value) -> mlua::Result<([u8; 3], Option<u8>)> {
+    if let mlua::Value::UserData(userdata) = value {
+        if let Ok(color) = userdata.borrow::<RGB>() {
+            return Ok(([color.0, color.1, color.2], None));
+        } else if let Ok(color) = userdata.borrow::<RGBA>() {
+            return Ok(([color.0, color.1, color.2], Some(color.3)));
+        }
+    }
+    Err(mlua::Error::runtime(
+        "expected a color from rgb(), rgba() or color()",
+    ))
+}
+
+/// Make a color from channels, an RGBA color if it has an alpha
+fn color_from_channels([r, g, b]: [u8; 3], alpha: Option<u8>) -> Color {
+    match alpha {
+        Some(a) => Color::RGBA(RGBA(r, g, b, a)),
+        None => Color::RGB(RGB(r, g, b)),
+    }
+}
+
+fn color_space_arg(space: Option<String>) -> mlua::Result<ColorSpace> {
+    match space {
+        Some(x) => x.parse().map_err(mlua::Error::runtime),
+        None => Ok(ColorSpace::Rgb),
+    }
+}
+
+fn opaque(([r, g, b], alpha): ([u8; 3], Option<u8>)) -> [u8; 4] {
+    [r, g, b, alpha.unwrap_or(255)]
+}
+
+impl FromLua for Image {
+    fn from_lua(value: mlua::Value, _: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::UserData(userdata) => Ok(userdata.borrow::<Image>()?.clone()),
+            _ => Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "Image".into(),
+                message: None,
+            }),
+        }
+    }
+}
+
+impl mlua::UserData for Image {
+    fn add_fields<F: mlua::UserDataFields<Self>>(fields: &mut F) {
+        fields.add_field_method_get("width", |_, this| Ok(this.width));
+        fields.add_field_method_get("height", |_, this| Ok(this.height));
+    }
+
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("tint", |_, this, (color,): (mlua::Value,)| {
+            Ok(this.tint(opaque(color_arg(&color)?)))
+        });
+        methods.add_method(
+            "replace",
+            |_, this, (from, to): (mlua::Value, mlua::Value)| {
+                Ok(this.replace_color(color_arg(&from)?, color_arg(&to)?))
+            },
+        );
+        methods.add_method("scale", |_, this, (scale,): (f64,)| {
+            if scale <= 0.0 {
+                return Err(mlua::Error::runtime(format!(
+                    "scale `{scale}` must be greater than 0"
+                )));
+            }
+            this.scale(scale)
+                .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+        });
+    }
+}
+
+/// Read the `exclude`, `rename` and `preserve` fields of the options table passed to `resource()`
+fn resource_options(
+    options: &mlua::Table,
+    pattern: &str,
+) -> mlua::Result<(Vec<glob::Pattern>, Option<String>, bool)> {
+    let exclude: Vec<String> = match options.get::<mlua::Value>("exclude")? {
+        mlua::Value::Nil => vec![],
+        mlua::Value::String(x) => vec![x.to_str()?.to_string()],
+        mlua::Value::Table(x) => x.sequence_values().collect::<mlua::Result<_>>()?,
+        _ => {
+            return Err(mlua::Error::runtime(
+                "resource(...) exclude must be a string or a list of strings",
+            ))
+        }
+    };
+    let exclude = exclude
+        .iter()
+        .map(|x| {
+            glob::Pattern::new(x).or(Err(mlua::Error::runtime(format!(
+                "invalid glob pattern: {x}"
+            ))))
+        })
+        .collect::<mlua::Result<_>>()?;
+
+    let rename: Option<String> = options.get("rename")?;
+    if let Some(rename) = &rename {
+        let known = placeholders(pattern);
+        if let Some(name) = placeholders(rename).iter().find(|x| !known.contains(x)) {
+            return Err(mlua::Error::runtime(format!(
+                "rename template uses a placeholder not in the pattern: {{{name}}}"
+            )));
+        }
+    }
+
+    let preserve: Option<bool> = options.get("preserve")?;
+
+    Ok((exclude, rename, preserve.unwrap_or(false)))
+}
+
+/// Location of the Lua code that called the current function, relative to the start of its chunk
+fn caller_location(lua: &mlua::Lua) -> ErrorLocation {
+    let line = lua
+        .inspect_stack(1)
+        .map(|x| x.curr_line())
+        .filter(|x| *x > 0)
+        .unwrap_or(0);
+    ErrorLocation {
+        line: line as u32,
+        column_utf8: 1,
+        column_ascii: 1,
+        ..Default::default()
+    }
+}
+
+/// The root folder of the theme sources, or the working directory if it isn't set
+fn project_root(lua: &mlua::Lua) -> std::io::Result<PathBuf> {
+    let root = match lua.app_data_ref::<ProjectRoot>() {
+        Some(root) => root.0.clone(),
+        None => std::env::current_dir()?,
+    };
+    Ok(match root.as_os_str().is_empty() {
+        true => PathBuf::from("."),
+        false => root,
+    })
+}
+
+/// Resolve a path relative to the folder of the file currently being processed
+fn source_relative_path(lua: &mlua::Lua, path: &str) -> PathBuf {
+    let dir = match lua.app_data_ref::<SourcePath>() {
+        Some(source) => source.0.parent().map(|x| x.to_path_buf()),
+        None => None,
+    };
+    RelativePathBuf::from(path).to_path(dir.unwrap_or_default())
+}
+
+/// Formats of data files that can be loaded from Lua
+#[derive(Debug, Clone, Copy)]
+enum DataFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// Convert a TOML value to JSON, keeping dates as strings
+fn toml_to_json(value: toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(x) => x.into(),
+        toml::Value::Integer(x) => x.into(),
+        toml::Value::Float(x) => x.into(),
+        toml::Value::Boolean(x) => x.into(),
+        toml::Value::Datetime(x) => x.to_string().into(),
+        toml::Value::Array(x) => x.into_iter().map(toml_to_json).collect(),
+        toml::Value::Table(x) => x.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect(),
+    }
+}
+
+/// Load a data file relative to the file currently being processed, as Lua tables. Files outside
+/// the root folder can't be loaded.
+fn load_data(lua: &mlua::Lua, path: &str, format: DataFormat) -> mlua::Result<mlua::Value> {
+    let path = source_relative_path(lua, path);
+    let error = |message: String| {
+        mlua::Error::runtime(format!(
+            "failed to load `{}`: {}",
+            path.to_string_lossy(),
+            message
+        ))
+    };
+
+    // a missing file is an input too, since creating it changes the result
+    let missing = |err: std::io::Error| {
+        cache::record(lua, |x| x.absent(&path));
+        error(err.to_string())
+    };
+    let canonical = path.canonicalize().map_err(missing)?;
+    if !canonical.starts_with(project_root(lua)?.canonicalize()?) {
+        return Err(error("the file is outside the root folder".into()));
+    }
+
+    let text = std::fs::read_to_string(&path).map_err(missing)?;
+    cache::record(lua, |x| x.file(&path, text.as_bytes()));
+    let value: serde_json::Value = match format {
+        DataFormat::Json => serde_json::from_str(&text).map_err(|err| error(err.to_string()))?,
+        DataFormat::Yaml => serde_yaml::from_str(&text).map_err(|err| error(err.to_string()))?,
+        DataFormat::Toml => {
+            toml_to_json(toml::from_str(&text).map_err(|err| error(err.to_string()))?)
+        }
+    };
+
+    // nulls become nil, and arrays are plain tables
+    lua.to_value_with(
+        &value,
+        mlua::SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .serialize_unit_to_null(false)
+            .set_array_metatable(false),
+    )
+}
+
+/// Load a PNG image relative to the file currently being processed. Images outside the root folder
+/// can't be loaded.
+fn load_image(lua: &mlua::Lua, path: &str) -> mlua::Result<Image> {
+    let path = source_relative_path(lua, path);
+    let error = |message: String| {
+        mlua::Error::runtime(format!(
+            "failed to load image `{}`: {}",
+            path.to_string_lossy(),
+            message
+        ))
+    };
+
+    // a missing file is an input too, since creating it changes the result
+    let missing = |err: std::io::Error| {
+        cache::record(lua, |x| x.absent(&path));
+        error(err.to_string())
+    };
+    let canonical = path.canonicalize().map_err(missing)?;
+    if !canonical.starts_with(project_root(lua)?.canonicalize()?) {
+        return Err(error("the file is outside the root folder".into()));
+    }
+
+    let bytes = std::fs::read(&path).map_err(missing)?;
+    cache::record(lua, |x| x.file(&path, &bytes));
+    let (image, _) = Image::decode(&bytes).map_err(|err| error(err.to_string()))?;
+    Ok(image)
+}
+
+/// Limits on the Lua code run while building a theme, so that untrusted theme sources can't hang
+/// or crash the build
+#[derive(Debug, Clone)]
+pub struct InterpreterOptions {
+    /// VM instructions each expression or script may run
+    instruction_limit: Option<u64>,
+    /// bytes of memory the interpreter may allocate in total
+    memory_limit: Option<usize>,
+    /// time each expression or script may run
+    timeout: Option<Duration>,
+    /// depth of nested function calls
+    max_depth: Option<usize>,
+    sandbox: SandboxProfile,
+    /// environment variables that `env()` may read. If not set, any variable may be read with the
+    /// standard profile and none with the strict profile.
+    env_allowlist: Option<Vec<String>>,
+    /// fixed time returned by `os.time()` and `os.date()` in seconds since the Unix epoch, for
+    /// reproducible output
+    build_time: Option<i64>,
+    /// seed of `math.random`, instead of a random one
+    random_seed: Option<i64>,
+}
+
+/// Which Lua functions are removed from the interpreter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SandboxProfile {
+    /// Remove functions that access files or the OS, and loading precompiled chunks
+    Standard,
+    /// Also remove `load`, `collectgarbage` and access to metatables other than those of tables,
+    /// and only allow reading allowlisted environment variables
+    Strict,
+}
+
+impl Default for InterpreterOptions {
+    fn default() -> Self {
+        Self {
+            instruction_limit: None,
+            memory_limit: Some(1024 * 1024 * 1024),
+            timeout: Some(Duration::from_secs(60)),
+            max_depth: Some(500),
+            sandbox: SandboxProfile::Standard,
+            env_allowlist: None,
+            build_time: None,
+            random_seed: None,
+        }
+    }
+}
+
+impl InterpreterOptions {
+    pub fn instruction_limit(mut self, x: Option<u64>) -> Self {
+        self.instruction_limit = x;
+        self
+    }
+
+    pub fn memory_limit(mut self, x: Option<usize>) -> Self {
+        self.memory_limit = x;
+        self
+    }
+
+    pub fn timeout(mut self, x: Option<Duration>) -> Self {
+        self.timeout = x;
+        self
+    }
+
+    pub fn max_depth(mut self, x: Option<usize>) -> Self {
+        self.max_depth = x;
+        self
+    }
+
+    pub fn sandbox(mut self, x: SandboxProfile) -> Self {
+        self.sandbox = x;
+        self
+    }
+
+    pub fn env_allowlist(mut self, x: Option<Vec<String>>) -> Self {
+        self.env_allowlist = x;
+        self
+    }
+
+    pub fn build_time(mut self, x: Option<i64>) -> Self {
+        self.build_time = x;
+        self
+    }
+
+    pub fn random_seed(mut self, x: Option<i64>) -> Self {
+        self.random_seed = x;
+        self
+    }
+
+    /// Whether `env()` may read an environment variable
+    fn allows_env(&self, name: &str) -> bool {
+        match &self.env_allowlist {
+            Some(names) => names.iter().any(|x| x == name),
+            None => self.sandbox == SandboxProfile::Standard,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone)]
+enum LimitError {
+    #[error("exceeded the limit of {0} instructions")]
+    Instructions(u64),
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("exceeded the limit of {0} nested function calls")]
+    Depth(usize),
+}
+
+/// Instructions run between checks of the instruction limit and timeout
+const INSTRUCTION_CHECK_INTERVAL: u32 = 1000;
+
+/// Progress of the expression or script being run, stored as Lua app data
+struct Execution {
+    started: Instant,
+    instructions: u64,
+    /// the first limit exceeded, which keeps failing until the next reset so `pcall` can't be
+    /// used to carry on
+    exceeded: Option<LimitError>,
+}
+
+/// Start counting instructions and time for a new expression or script
+pub fn reset_limits(lua: &mlua::Lua) {
+    if let Some(mut execution) = lua.app_data_mut::<Execution>() {
+        execution.started = Instant::now();
+        execution.instructions = 0;
+        execution.exceeded = None;
+    }
+}
+
+/// Fail if the expression or script being run exceeded a limit, even if the error was caught
+pub fn check_limits(lua: &mlua::Lua) -> mlua::Result<()> {
+    match lua
+        .app_data_ref::<Execution>()
+        .and_then(|x| x.exceeded.clone())
+    {
+        Some(err) => Err(mlua::Error::ExternalError(Arc::new(err))),
+        None => Ok(()),
+    }
+}
+
+/// Convert a date table like the one taken by `os.time` to seconds since the Unix epoch, reading it
+/// as UTC. Fields out of their range are carried over, e.g. month 13 is January of the next year.
+fn utc_time(table: mlua::Table) -> mlua::Result<i64> {
+    let field = |name: &str, default: Option<i64>| -> mlua::Result<i64> {
+        match (table.get::<Option<i64>>(name)?, default) {
+            (Some(x), _) | (None, Some(x)) => Ok(x),
+            (None, None) => Err(mlua::Error::runtime(format!(
+                "field '{name}' missing in date table"
+            ))),
+        }
+    };
+    let months = field("year", None)? * 12 + field("month", None)? - 1;
+    let (year, month) = (months.div_euclid(12), months.rem_euclid(12) + 1);
+
+    // days since the epoch from a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468 + field("day", None)? - 1;
+
+    Ok(days * 86400
+        + field("hour", Some(12))? * 3600
+        + field("min", Some(0))? * 60
+        + field("sec", Some(0))?)
+}
+
+/// Set `BUILD_TIME`, and make the time and random functions reproducible if a build time or seed
+/// is given
+fn set_time(lua: &mlua::Lua, options: &InterpreterOptions) {
+    let build_time = options.build_time.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs() as i64)
+            .unwrap_or_default()
+    });
+    lua.globals().set("BUILD_TIME", build_time).unwrap();
+
+    if options.build_time.is_some() {
+        // dates are read and formatted in UTC, so they don't depend on the time zone of the machine
+        let utc_time = lua
+            .create_function(|_, table: mlua::Table| utc_time(table))
+            .unwrap();
+        lua.load(
+            r#"
+            local build_time, utc_time = ...
+            local date, pairs = os.date, pairs
+            os.time = function(t)
+                if t == nil then
+                    return build_time
+                end
+                local time = utc_time(t)
+                -- like `os.time`, normalize the fields of the table
+                for key, value in pairs(date("!*t", time)) do
+                    t[key] = value
+                end
+                return time
+            end
+            os.date = function(format, t)
+                format = format or "%c"
+                if format:sub(1, 1) ~= "!" then
+                    format = "!" .. format
+                end
+                return date(format, t or build_time)
+            end
+            os.clock = function()
+                return 0
+            end
+            "#,
+        )
+        .call::<()>((build_time, utc_time))
+        .unwrap();
+    }
+
+    if let Some(seed) = options.random_seed {
+        // calling `math.randomseed()` without a seed would make it random again
+        lua.load(
+            r##"
+            local seed = ...
+            local randomseed = math.randomseed
+            math.randomseed = function(...)
+                if select("#", ...) == 0 then
+                    return randomseed(seed)
+                end
+                return randomseed(...)
+            end
+            randomseed(seed)
+            "##,
+        )
+        .call::<()>(seed)
+        .unwrap();
+    }
+}
+
+fn set_limits(lua: &mlua::Lua, options: &InterpreterOptions) {
+    if let Some(limit) = options.memory_limit {
+        lua.set_memory_limit(limit).unwrap();
+    }
+
+    let InterpreterOptions {
+        instruction_limit,
+        timeout,
+        max_depth,
+        ..
+    } = *options;
+    let count = instruction_limit.is_some() || timeout.is_some();
+    if !count && max_depth.is_none() {
+        return;
+    }
+
+    lua.set_app_data(Execution {
+        started: Instant::now(),
+        instructions: 0,
+        exceeded: None,
+    });
+    let triggers = mlua::HookTriggers {
+        on_calls: max_depth.is_some(),
+        every_nth_instruction: count.then_some(INSTRUCTION_CHECK_INTERVAL),
+        ..Default::default()
+    };
+    let hook = Rc::new(move |lua: &mlua::Lua, debug: mlua::Debug| {
+        let Some(mut execution) = lua.app_data_mut::<Execution>() else {
+            return Ok(mlua::VmState::Continue);
+        };
+        if execution.exceeded.is_none() {
+            execution.exceeded = match debug.event() {
+                mlua::DebugEvent::Count => {
+                    execution.instructions += INSTRUCTION_CHECK_INTERVAL as u64;
+                    if let Some(limit) = instruction_limit.filter(|x| execution.instructions > *x) {
+                        Some(LimitError::Instructions(limit))
+                    } else {
+                        timeout
+                            .filter(|x| execution.started.elapsed() > *x)
+                            .map(LimitError::Timeout)
+                    }
+                }
+                // there is a stack frame this deep only if the calls are nested too deeply
+                mlua::DebugEvent::Call => max_depth
+                    .filter(|x| lua.inspect_stack(*x).is_some())
+                    .map(LimitError::Depth),
+                _ => None,
+            };
+        }
+        match &execution.exceeded {
+            Some(err) => Err(mlua::Error::ExternalError(Arc::new(err.clone()))),
+            None => Ok(mlua::VmState::Continue),
+        }
+    });
+    let set_hook = move |thread: &mlua::Thread| {
+        let hook = hook.clone();
+        thread.set_hook(triggers, move |lua, debug| hook(lua, debug));
+    };
+    set_hook(&lua.current_thread());
+
+    // only one thread can have the hook, so move it to each coroutine while it runs. The resume
+    // itself happens in Lua, so error values that aren't strings reach the caller unchanged.
+    let enter_hook = set_hook.clone();
+    let enter = lua
+        .create_function(move |_, thread: mlua::Thread| {
+            enter_hook(&thread);
+            Ok(())
+        })
+        .unwrap();
+    let leave = lua
+        .create_function(move |lua, ()| {
+            set_hook(&lua.current_thread());
+            check_limits(lua)
+        })
+        .unwrap();
+    let coroutine: mlua::Table = lua.globals().get("coroutine").unwrap();
+    let resume: mlua::Function = lua
+        .load(
+            r#"
+            local resume, enter, leave = ...
+            local pack, unpack = table.pack, table.unpack
+            return function(thread, ...)
+                enter(thread)
+                local result = pack(resume(thread, ...))
+                leave()
+                return unpack(result, 1, result.n)
+            end
+            "#,
+        )
+        .call((
+            coroutine.get::<mlua::Function>("resume").unwrap(),
+            enter,
+            leave,
+        ))
+        .unwrap();
+    coroutine.set("resume", &resume).unwrap();
+    let wrap: mlua::Function = lua
+        .load(
+            r#"
+            local resume = ...
+            local create, error, pack, unpack = coroutine.create, error, table.pack, table.unpack
+            return function(f)
+                local thread = create(f)
+                return function(...)
+                    local result = pack(resume(thread, ...))
+                    if not result[1] then
+                        error(result[2], 0)
+                    end
+                    return unpack(result, 2, result.n)
+                end
+            end
+            "#,
+        )
+        .call(resume)
+        .unwrap();
+    coroutine.set("wrap", wrap).unwrap();
+
+    // a caught limit error is raised again, so the code around `pcall` can't keep running
+    let check = lua.create_function(|lua, ()| check_limits(lua)).unwrap();
+    lua.load(
+        r#"
+        local check = ...
+        local function checked(...)
+            check()
+            return ...
+        end
+        local pcall, xpcall = pcall, xpcall
+        _G.pcall = function(...)
+            return checked(pcall(...))
+        end
+        _G.xpcall = function(...)
+            return checked(xpcall(...))
+        end
+        "#,
+    )
+    .call::<()>(check)
+    .unwrap();
+}
+
+/// Name of the registry table caching the value returned by each module, keyed by its path.
+/// Modules being loaded are marked with `false`.
+const LOADED_MODULES: &str = "loaded_modules";
+
+/// Paths relative to the root folder that a module may be loaded from, e.g. `lib/palette` or
+/// `lib.palette` can be `lib/palette.lua` or `lib/palette/init.lua`
+fn module_paths(name: &str) -> Result<Vec<RelativePathBuf>, ModuleError> {
+    let outside_root = || ModuleError::OutsideRoot(name.to_string());
+    let name = name.replace('\\', "/");
+    if name.starts_with('/') || name.contains(':') {
+        return Err(outside_root());
+    }
+
+    if name.ends_with(".lua") {
+        let path = RelativePathBuf::from(name.as_str()).normalize();
+        if path.as_str().starts_with("..") {
+            return Err(outside_root());
+        }
+        return Ok(vec![path]);
+    }
+
+    // dots separate folders, unless the name is already a path
+    let base = match name.contains('/') {
+        true => RelativePathBuf::from(name.as_str()),
+        false => RelativePathBuf::from(name.replace('.', "/")),
+    }
+    .normalize();
+    if base.as_str().is_empty() || base.as_str().starts_with("..") {
+        return Err(outside_root());
+    }
+    Ok(vec![
+        RelativePathBuf::from(format!("{}.lua", base)),
+        base.join("init.lua"),
+    ])
+}
+
+/// Load a module relative to the root folder. Each module runs once with its own global scope
+/// that falls back to the shared globals, and its return value is cached. Modules that don't
+/// return anything give their scope, so their functions can be used like `palette.dim(...)`.
+fn require(lua: &mlua::Lua, name: String) -> mlua::Result<mlua::Value> {
+    let external = |err: ModuleError| mlua::Error::ExternalError(Arc::new(err));
+
+    let root = project_root(lua)?;
+    let candidates: Vec<PathBuf> = module_paths(&name)
+        .map_err(external)?
+        .iter()
+        .map(|x| x.to_path(&root))
+        .collect();
+    let found = candidates.iter().find(|x| x.is_file());
+    // creating a file that would be found first changes the result
+    for candidate in candidates.iter().take_while(|x| Some(*x) != found) {
+        cache::record(lua, |x| x.absent(candidate));
+    }
+    let Some(path) = found else {
+        let tried: Vec<String> = candidates
+            .iter()
+            .map(|x| format!("`{}`", x.to_string_lossy()))
+            .collect();
+        return Err(external(ModuleError::NotFound(name, tried.join(", "))));
+    };
+
+    // symbolic links may still point outside the root folder
+    let canonical = path.canonicalize()?;
+    if !canonical.starts_with(root.canonicalize()?) {
+        return Err(external(ModuleError::OutsideRoot(name)));
+    }
+
+    let loaded = match lua.named_registry_value::<Option<mlua::Table>>(LOADED_MODULES)? {
+        Some(x) => x,
+        None => {
+            let table = lua.create_table()?;
+            lua.set_named_registry_value(LOADED_MODULES, &table)?;
+            table
+        }
+    };
+    let key = canonical.to_string_lossy().to_string();
+    match loaded.raw_get::<mlua::Value>(key.as_str())? {
+        mlua::Value::Nil => (),
+        mlua::Value::Boolean(false) => return Err(external(ModuleError::Circular(name))),
+        value => return Ok(value),
+    }
+
+    let source = std::fs::read_to_string(path).map_err(|err| {
+        mlua::Error::runtime(format!(
+            "failed to read module `{}`: {}",
+            path.to_string_lossy(),
+            err
+        ))
+    })?;
+    cache::record(lua, |x| x.file(path, source.as_bytes()));
+
+    // globals set by the module go in its own table, reads fall back to the shared globals
+    let env = lua.create_table()?;
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.globals())?;
+    env.set_metatable(Some(meta));
+
+    loaded.raw_set(key.as_str(), false)?;
+    let previous_source = lua.set_app_data(SourcePath(path.clone()));
+    let result = lua
+        .load(source)
+        .set_name(path.to_string_lossy())
+        .set_environment(env.clone())
+        .call::<mlua::Value>(());
+    match previous_source {
+        Some(x) => lua.set_app_data(x),
+        None => lua.remove_app_data::<SourcePath>(),
+    };
+
+    let value = match result {
+        Ok(mlua::Value::Nil) => mlua::Value::Table(env),
+        Ok(value) => value,
+        Err(err) => {
+            loaded.raw_set(key.as_str(), mlua::Value::Nil)?;
+            return Err(err);
+        }
+    };
+    loaded.raw_set(key.as_str(), &value)?;
+    Ok(value)
+}
+
 fn unset(table: &mlua::Table, key: &str) {
     table.set(key, None::<bool>).unwrap();
 }
@@ -301,7 +1565,7 @@ fn whitelist(table: &mlua::Table, keys: Vec<&str>) {
         .unwrap();
 }
 
-fn sandbox_lua(lua: &mlua::Lua) {
+fn sandbox_lua(lua: &mlua::Lua, profile: SandboxProfile) {
     let globals = lua.globals();
 
     // unset globals for sandboxing
@@ -316,15 +1580,61 @@ fn sandbox_lua(lua: &mlua::Lua) {
         &globals.get("os").unwrap(),
         vec!["clock", "date", "difftime", "time"],
     );
+
+    // precompiled chunks can corrupt the interpreter, so only allow loading text
+    unset(&globals.get("string").unwrap(), "dump");
+    let load: mlua::Function = lua
+        .load(
+            r#"
+            local load = load
+            local select = select
+            -- passing a nil env would make the chunk's _ENV nil, so only forward it when given
+            return function(chunk, name, mode, ...)
+                if select('#', ...) > 0 then
+                    return load(chunk, name, "t", ...)
+                end
+                return load(chunk, name, "t")
+            end
+            "#,
+        )
+        .eval()
+        .unwrap();
+    globals.set("load", load).unwrap();
+
+    if profile == SandboxProfile::Strict {
+        unset(&globals, "load");
+        unset(&globals, "collectgarbage");
+
+        // the string metatable is shared by all code, and userdata metatables hold the methods
+        // of colors and images
+        let getmetatable: mlua::Function = lua
+            .load(
+                r#"
+                local getmetatable, type = getmetatable, type
+                getmetatable("").__metatable = false
+                return function(value)
+                    if type(value) == "table" then
+                        return getmetatable(value)
+                    end
+                    return nil
+                end
+                "#,
+            )
+            .eval()
+            .unwrap();
+        globals.set("getmetatable", getmetatable).unwrap();
+    }
 }
 
-pub fn new() -> mlua::Lua {
+pub fn new_with_options(options: &InterpreterOptions) -> mlua::Lua {
     // sandbox lua following Roblox's guide:
     // https://luau-lang.org/sandbox
 
     let lua = mlua::Lua::new();
 
-    sandbox_lua(&lua);
+    sandbox_lua(&lua, options.sandbox);
+    set_time(&lua, options);
+    set_limits(&lua, options);
 
     {
         let globals = lua.globals();
@@ -354,97 +1664,267 @@ pub fn new() -> mlua::Lua {
         globals.set("rgba", func).unwrap();
 
         let func = lua
-            .create_function(|_, (mode, frac): (String, f32)| {
-                // the blend mode is a 18-bit value, split into multiple parts:
-                //
-                //     0b1 frac_____ mode____
-                //     0b1 100000000 11111110
-
-                // reaper's frac value is represented as a fraction: x / 256
-                // we need to find the nearest x value
-                if !(0f32 <= frac && frac <= 1f32) {
-                    return Err(mlua::Error::RuntimeError(format!(
-                        "frac `{}` must be a value between 0.0 and 1.0",
-                        frac
+            .create_function(|_, (mode, frac): (mlua::Value, f64)| {
+                let mode = match mode {
+                    mlua::Value::String(x) => x.to_str()?.parse(),
+                    mlua::Value::Integer(x) => u8::try_from(x)
+                        .map(BlendMode::from_byte)
+                        .map_err(|_| BlendError::UnknownMode(x.to_string())),
+                    x => Err(BlendError::UnknownMode(x.to_string()?)),
+                };
+                mode.and_then(|mode| Blend::new(mode, frac))
+                    .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+            })
+            .unwrap();
+        globals.set("blend", func).unwrap();
+
+        let func = lua
+            .create_function(|_, value: i64| Ok(Blend::decode(value)))
+            .unwrap();
+        globals.set("decode_blend", func).unwrap();
+
+        let func = lua
+            .create_function(|_, (color, enabled): (mlua::Value, bool)| {
+                let (rgb, alpha) = color_arg(&color)?;
+                Ok(Toggle {
+                    color: color_from_channels(rgb, alpha),
+                    enabled,
+                })
+            })
+            .unwrap();
+        globals.set("toggle", func).unwrap();
+
+        let func = lua
+            .create_function(|_, value: i64| {
+                Toggle::decode(value).map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+            })
+            .unwrap();
+        globals.set("decode_toggle", func).unwrap();
+
+        // function to get an environment variable
+        let options = options.clone();
+        let func = lua
+            .create_function(move |lua, (name,): (String,)| {
+                if !options.allows_env(&name) {
+                    return Err(mlua::Error::runtime(format!(
+                        "reading the environment variable `{}` is not allowed",
+                        name
                     )));
                 }
+                let value = std::env::var(&name);
+                cache::record(lua, |x| x.env(&name, value.as_deref().ok()));
+                value.map_err(mlua::Error::external)
+            })
+            .unwrap();
+        globals.set("env", func).unwrap();
 
-                let frac: u32 = (frac * 256f32).round() as u32;
+        // data files, e.g. palettes shared with other tools
+        for (name, format) in [
+            ("load_json", DataFormat::Json),
+            ("load_yaml", DataFormat::Yaml),
+            ("load_toml", DataFormat::Toml),
+        ] {
+            let func = lua
+                .create_function(move |lua, (path,): (String,)| load_data(lua, &path, format))
+                .unwrap();
+            globals.set(name, func).unwrap();
+        }
 
-                // the mode value is just an enum
-                let mode: u32 = match mode.as_str() {
-                    "normal" => 0b00000000,
-                    "add" => 0b00000001,
-                    "overlay" => 0b00000100,
-                    "multiply" => 0b00000011,
-                    "dodge" => 0b00000010,
-                    "hsv" => 0b11111110,
-                    _ => {
-                        return Err(mlua::Error::RuntimeError(format!(
-                            // I'm formatting this string weirdly because cargo fmt refuses to
-                            // format this file if i put the blend mode list in the base string
-                            "mode `{}` must be one of: {}",
-                            mode,
-                            "\"normal\", \"add\", \"overlay\", \"multiply\", \"dodge\", \"hsv\""
-                        )));
-                    }
-                };
+        // load a module relative to the root folder
+        let func = lua
+            .create_function(|lua, (name,): (String,)| require(lua, name))
+            .unwrap();
+        globals.set("require", func).unwrap();
 
-                let result = 0b100000000000000000 + (frac << 8) + mode;
+        // images generated at build time
+        let func = lua
+            .create_function(|lua, (path,): (String,)| load_image(lua, &path))
+            .unwrap();
+        globals.set("load_image", func).unwrap();
 
-                Ok(result)
+        let func = lua
+            .create_function(|_, (width, height, color): (u32, u32, mlua::Value)| {
+                Image::fill(width, height, opaque(color_arg(&color)?))
+                    .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
             })
             .unwrap();
-        globals.set("blend", func).unwrap();
+        globals.set("fill_image", func).unwrap();
 
-        // function to get an environment variable
         let func = lua
-            .create_function(|_, (name,): (String,)| {
-                std::env::var(&name).map_err(|err| mlua::Error::external(err))
+            .create_function(
+                |_,
+                 (width, height, from, to, direction): (
+                    u32,
+                    u32,
+                    mlua::Value,
+                    mlua::Value,
+                    Option<String>,
+                )| {
+                    let vertical = match direction.as_deref() {
+                        None | Some("vertical") => true,
+                        Some("horizontal") => false,
+                        Some(x) => {
+                            return Err(mlua::Error::runtime(format!(
+                                "direction `{}` must be one of: \"vertical\", \"horizontal\"",
+                                x
+                            )))
+                        }
+                    };
+                    Image::gradient(
+                        width,
+                        height,
+                        opaque(color_arg(&from)?),
+                        opaque(color_arg(&to)?),
+                        vertical,
+                    )
+                    .map_err(|err| mlua::Error::ExternalError(Arc::new(err)))
+                },
+            )
+            .unwrap();
+        globals.set("gradient_image", func).unwrap();
+
+        // families of related colors
+        let func =
+            lua
+                .create_function(
+                    |lua,
+                     (from, to, count, space): (
+                        mlua::Value,
+                        mlua::Value,
+                        usize,
+                        Option<String>,
+                    )| {
+                        let space = color_space_arg(space)?;
+                        let (from, from_alpha) = color_arg(&from)?;
+                        let (to, to_alpha) = color_arg(&to)?;
+                        // RGB colors are opaque when mixed with RGBA colors
+                        let alpha = match (from_alpha, to_alpha) {
+                            (None, None) => None,
+                            (a, b) => Some((a.unwrap_or(255), b.unwrap_or(255))),
+                        };
+
+                        let colors = lua.create_table()?;
+                        for t in palette::steps(count) {
+                            let rgb = palette::interpolate(from, to, t, space);
+                            let alpha = alpha
+                                .map(|(a, b)| (a as f64 + (b as f64 - a as f64) * t).round() as u8);
+                            colors.push(color_from_channels(rgb, alpha))?;
+                        }
+                        Ok(colors)
+                    },
+                )
+                .unwrap();
+        globals.set("gradient", func).unwrap();
+
+        let func = lua
+            .create_function(|lua, (base, options): (mlua::Value, Option<mlua::Table>)| {
+                let (base, alpha) = color_arg(&base)?;
+                let (hues, lightness) = match options {
+                    Some(options) => (
+                        options.get::<Option<usize>>("hues")?,
+                        options.get::<Option<Vec<f64>>>("lightness")?,
+                    ),
+                    None => (None, None),
+                };
+                let hues = hues.unwrap_or(1);
+                let lightness = lightness.unwrap_or(vec![0.0]);
+
+                let colors = lua.create_table()?;
+                for i in 0..hues {
+                    let hue = 360.0 * i as f64 / hues as f64;
+                    for lightness in &lightness {
+                        let rgb = palette::adjust(base, hue, *lightness);
+                        colors.push(color_from_channels(rgb, alpha))?;
+                    }
+                }
+                Ok(colors)
             })
             .unwrap();
-        globals.set("env", func).unwrap();
+        globals.set("palette", func).unwrap();
 
         // allow adding resouce in lua code
         let func = lua
-            .create_function(|_, vals: mlua::Variadic<String>| -> mlua::Result<()> {
-                if vals.len() == 1 {
-                    let pattern = vals.get(0).unwrap();
-                    let pattern = glob::Pattern::new(pattern).or(Err(mlua::Error::runtime(
-                        format!("invalid glob pattern: {pattern}"),
-                    )))?;
-
-                    let dest = RelativePathBuf::from(".").normalize();
-
-                    {
-                        let mut paths = NEW_RESOURCE_PATHS.lock().unwrap();
-                        paths.push(Directive::Resource { pattern, dest })
+            .create_function(|lua, mut vals: mlua::Variadic<mlua::Value>| -> mlua::Result<()> {
+                // an options table may be given as the last argument
+                let options = match vals.last() {
+                    Some(mlua::Value::Table(table)) => {
+                        let table = table.clone();
+                        vals.pop();
+                        Some(table)
                     }
+                    _ => None,
+                };
 
-                    Ok(())
-                } else if vals.len() == 2 {
-                    let dest = vals.get(0).unwrap();
-                    let dest = RelativePathBuf::from(dest).normalize();
-
-                    let pattern = vals.get(1).unwrap();
-                    let pattern = glob::Pattern::new(pattern).or(Err(mlua::Error::runtime(
-                        format!("invalid glob pattern: {pattern}"),
-                    )))?;
+                // a generated image, e.g. resource("knob.png", load_image("knob.png"):tint(...))
+                if let [mlua::Value::String(dest), mlua::Value::UserData(image)] = vals.as_slice() {
+                    if let Ok(image) = image.borrow::<Image>() {
+                        if options.is_some() {
+                            return Err(mlua::Error::runtime(
+                                "resource(...) options can't be used with generated images",
+                            ));
+                        }
+                        let dest = RelativePathBuf::from(dest.to_str()?.as_ref()).normalize();
+                        push_generated_resource(lua, dest, Resource::Generated(image.encode()));
+                        return Ok(());
+                    }
+                }
 
-                    {
-                        let mut paths = NEW_RESOURCE_PATHS.lock().unwrap();
-                        paths.push(Directive::Resource { pattern, dest })
+                let strings = vals
+                    .iter()
+                    .map(|x| match x {
+                        mlua::Value::String(x) => Ok(x.to_str()?.to_string()),
+                        _ => Err(mlua::Error::runtime(
+                            "resource(...) destination and pattern must be strings",
+                        )),
+                    })
+                    .collect::<mlua::Result<Vec<_>>>()?;
+
+                let (dest, pattern) = match strings.as_slice() {
+                    [pattern] => (RelativePathBuf::from(".").normalize(), pattern),
+                    [dest, pattern] => (RelativePathBuf::from(dest).normalize(), pattern),
+                    _ => {
+                        return Err(mlua::Error::runtime(
+                            "resource(...) can only be called with 1 or 2 arguments, optionally followed by an options table",
+                        ))
                     }
+                };
+                let (exclude, rename, preserve) = match options {
+                    Some(options) => resource_options(&options, pattern)?,
+                    None => (vec![], None, false),
+                };
+                let pattern = resource_pattern(pattern, rename.is_some()).or(Err(
+                    mlua::Error::runtime(format!("invalid glob pattern: {pattern}")),
+                ))?;
+
+                let location = caller_location(lua);
+                push_resource_directive(
+                    lua,
+                    ResourceDirective {
+                        pattern,
+                        dest,
+                        exclude,
+                        rename,
+                        preserve,
+                        location,
+                    },
+                );
 
-                    Ok(())
-                } else {
-                    Err(mlua::Error::runtime(
-                        "resource(...) can only be called with 1 or 2 arguments",
-                    ))
-                }
+                Ok(())
             })
             .unwrap();
         globals.set("resource", func).unwrap();
+
+        // add a generated text file, e.g. a readme or a JSON manifest
+        let func = lua
+            .create_function(|lua, (dest, contents): (String, mlua::String)| {
+                let dest = RelativePathBuf::from(dest).normalize();
+                // Lua strings may contain any bytes, so they are written as they are
+                let resource = Resource::Generated(contents.as_bytes().to_vec());
+                push_generated_resource(lua, dest, resource);
+                Ok(())
+            })
+            .unwrap();
+        globals.set("resource_text", func).unwrap();
     }
 
     lua
@@ -453,10 +1933,11 @@ pub fn new() -> mlua::Lua {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempDir;
 
     #[test]
     fn test_rgb() {
-        let lua = new();
+        let lua = new_with_options(&InterpreterOptions::default());
 
         let result: RGB = lua.load("rgb(255, 255, 255)").eval().unwrap();
         let expected = RGB(255, 255, 255);
@@ -477,7 +1958,7 @@ mod tests {
 
     #[test]
     fn test_rgb_reuse() {
-        let lua = new();
+        let lua = new_with_options(&InterpreterOptions::default());
 
         lua.load("foo = rgb(11, 22, 33)").exec().unwrap();
 
@@ -496,7 +1977,7 @@ mod tests {
 
     #[test]
     fn test_arr() {
-        let lua = new();
+        let lua = new_with_options(&InterpreterOptions::default());
 
         let result: String = lua.load("rgb(1, 2, 3):arr()").eval().unwrap();
         let expected = "1 2 3";
@@ -509,7 +1990,7 @@ mod tests {
 
     #[test]
     fn test_color() {
-        let lua = new();
+        let lua = new_with_options(&InterpreterOptions::default());
 
         let result: RGB = lua.load("color(0xffffff)").eval().unwrap();
         let expected = RGB(255, 255, 255);
@@ -524,21 +2005,266 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_toggle() {
+        let lua = new_with_options(&InterpreterOptions::default());
+        let string = |code: &str| {
+            lua.load(code)
+                .eval::<mlua::Value>()
+                .unwrap()
+                .to_string()
+                .unwrap()
+        };
+
+        let result: i64 = lua.load("rgb(1, 2, 3):negative()").eval().unwrap();
+        assert_eq!(result, 0x030201 - 0x1000000);
+        let result: i64 = lua.load("rgba(1, 2, 3, 4):negative()").eval().unwrap();
+        assert_eq!(result, 0x04030201 - 0x100000000);
+
+        let result: Toggle = lua.load("toggle(rgb(1, 2, 3), false)").eval().unwrap();
+        assert_eq!(result.value(), 0x030201 - 0x1000000);
+        let result: Toggle = lua.load("toggle(rgba(1, 2, 3, 4), true)").eval().unwrap();
+        assert_eq!(result.value(), 0x04030201);
+
+        for value in [
+            0,
+            0x030201,
+            0x04030201,
+            -1,
+            -0x1000000,
+            -0x1000001,
+            -0xff000000,
+            -0xff000001,
+            -0x100000000,
+        ] {
+            assert_eq!(Toggle::decode(value).unwrap().value(), value);
+        }
+        assert!(Toggle::decode(0x100000000).is_err());
+        assert!(Toggle::decode(-0x100000001).is_err());
+
+        for alpha in 0..=255 {
+            for enabled in [true, false] {
+                let toggle = Toggle {
+                    color: Color::RGBA(RGBA(1, 2, 3, alpha)),
+                    enabled,
+                };
+                let decoded = Toggle::decode(toggle.value()).unwrap();
+                assert_eq!(decoded.value(), toggle.value(), "{toggle}");
+                assert_eq!(decoded.enabled, enabled, "{toggle}");
+            }
+        }
+        let result: Toggle = lua.load("toggle(rgba(1, 2, 3, 0), false)").eval().unwrap();
+        assert_eq!(result.value(), 0x030201 - 0x100000000);
+        assert_eq!(Toggle::decode(result.value()).unwrap(), result);
+
+        assert_eq!(
+            string("return tostring(decode_toggle(rgb(1, 2, 3):negative()))"),
+            "toggle(rgb(1, 2, 3), false)"
+        );
+        assert_eq!(
+            string("return tostring(decode_toggle('67305985'))"),
+            "toggle(rgba(1, 2, 3, 4), true)"
+        );
+        assert_eq!(
+            string("local x = decode_toggle(-1) return tostring(x.color) .. ' ' .. tostring(x.enabled)"),
+            "rgb(255, 255, 255) false"
+        );
+        assert_eq!(
+            string("return tostring(toggle(rgb(1, 2, 3), true) == decode_toggle(0x030201))"),
+            "true"
+        );
+    }
+
     #[test]
     fn test_blend() {
-        let lua = new();
+        let lua = new_with_options(&InterpreterOptions::default());
 
-        let result: i32 = lua.load("blend('hsv', 0.12)").eval().unwrap();
+        let result: Blend = lua.load("blend('hsv', 0.12)").eval().unwrap();
         let expected = 0b100001111111111110;
-        assert_eq!(result, expected);
+        assert_eq!(result.value(), expected);
 
-        let result: i32 = lua.load("blend('normal', 0)").eval().unwrap();
+        let result: Blend = lua.load("blend('normal', 0)").eval().unwrap();
         let expected = 0b100000000000000000;
-        assert_eq!(result, expected);
+        assert_eq!(result.value(), expected);
 
-        let result: i32 = lua.load("blend('normal', 1)").eval().unwrap();
+        let result: Blend = lua.load("blend('normal', 1)").eval().unwrap();
         let expected = 0b110000000000000000;
-        assert_eq!(result, expected);
+        assert_eq!(result.value(), expected);
+
+        let result: Blend = lua.load("blend(5, 0.5)").eval().unwrap();
+        assert_eq!(result.value(), 0x28005);
+        assert!(lua.load("blend('screen', 0.5)").exec().is_err());
+        assert!(lua.load("blend('add', 1.5)").exec().is_err());
+        assert!(lua.load("blend(256, 0.5)").exec().is_err());
+
+        // blend values still work like the numbers they replaced
+        let result: i64 = lua.load("blend(5, 0.5) + 1").eval().unwrap();
+        assert_eq!(result, 0x28006);
+        let result: i64 = lua.load("1 + blend(5, 0.5)").eval().unwrap();
+        assert_eq!(result, 0x28006);
+        let result: i64 = lua.load("blend(5, 0.5) - blend(5, 0)").eval().unwrap();
+        assert_eq!(result, 0x8000);
+        let result: f64 = lua.load("blend(5, 0.5) + 0.5").eval().unwrap();
+        assert_eq!(result, 0x28005 as f64 + 0.5);
+        let result: bool = lua
+            .load("blend(5, 0.5) < 0x30000 and 0x20000 <= blend(5, 0.5)")
+            .eval()
+            .unwrap();
+        assert!(result);
+        let result: bool = lua.load("blend(5, 0.5).value == 0x28005").eval().unwrap();
+        assert!(result);
+        assert!(lua.load("blend(5, 0.5) + 'x'").exec().is_err());
+    }
+
+    #[test]
+    fn test_decode_blend() {
+        let lua = new_with_options(&InterpreterOptions::default());
+        let string = |code: &str| {
+            lua.load(code)
+                .eval::<mlua::Value>()
+                .unwrap()
+                .to_string()
+                .unwrap()
+        };
+
+        for value in [0x20000, 0x30000, 0x21ffe, 0x28005, -1, 0, 0xff, 0x40000] {
+            assert_eq!(Blend::decode(value).value(), value);
+        }
+        assert_eq!(Blend::decode(-1), Blend::Raw(-1));
+        // frac can't be more than 256
+        assert_eq!(Blend::decode(0x30100), Blend::Raw(0x30100));
+
+        assert_eq!(
+            string("local x = decode_blend(170240) return x.mode .. ' ' .. x.frac"),
+            "normal 0.59765625"
+        );
+        assert_eq!(string("return decode_blend('138753').mode"), "add");
+        assert_eq!(
+            string("return tostring(decode_blend(0x28005))"),
+            "blend(5, 0.5)"
+        );
+        assert_eq!(
+            string("return tostring(decode_blend(-1))"),
+            "decode_blend(-1)"
+        );
+        assert_eq!(
+            string("return tostring(decode_blend(blend('hsv', 0.5).value) == blend('hsv', 0.5))"),
+            "true"
+        );
+        assert_eq!(
+            lua.load("decode_blend(-1).mode")
+                .eval::<mlua::Value>()
+                .unwrap(),
+            mlua::Value::Nil
+        );
+    }
+
+    #[test]
+    fn test_image() {
+        let lua = new_with_options(&InterpreterOptions::default());
+
+        let result: Image = lua
+            .load("fill_image(2, 1, rgb(10, 20, 30)):tint(rgba(255, 0, 255, 128))")
+            .eval()
+            .unwrap();
+        assert_eq!(result.pixels, [[10, 0, 30, 128]; 2]);
+
+        let result: (u32, u32) = lua
+            .load(
+                r#"
+                local image = gradient_image(4, 2, rgb(0, 0, 0), rgb(255, 255, 255), "horizontal")
+                    :replace(rgb(0, 0, 0), rgba(1, 2, 3, 4))
+                    :scale(1.5)
+                return image.width, image.height
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(result, (6, 3));
+
+        let result: mlua::Result<Image> = lua
+            .load("gradient_image(1, 1, rgb(0, 0, 0), rgb(0, 0, 0), 'diagonal')")
+            .eval();
+        assert!(result.is_err());
+
+        let result: mlua::Result<Image> = lua.load("load_image('missing.png')").eval();
+        assert!(result.is_err());
+
+        let error = |code: &str| lua.load(code).eval::<Image>().unwrap_err().to_string();
+        assert!(error("fill_image(100000, 100000, rgb(0, 0, 0))").contains("too large"));
+        assert!(error("fill_image(10, 10, rgb(0, 0, 0)):scale(1e9)").contains("too large"));
+        // the area is limited too, not just each side
+        assert!(error("fill_image(16384, 16384, rgb(0, 0, 0))").contains("too large"));
+        assert!(
+            error("gradient_image(5000, 5000, rgb(0, 0, 0), rgb(0, 0, 0), 'vertical')")
+                .contains("too large")
+        );
+        assert!(error("fill_image(3000, 3000, rgb(0, 0, 0)):scale(2)").contains("too large"));
+
+        // images are loaded relative to the file being processed, but must stay in the root
+        let parent = TempDir::new("load-image");
+        let root = parent.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        let png = Image::fill(2, 1, [1, 2, 3, 255]).unwrap().encode();
+        std::fs::write(root.join("knob.png"), &png).unwrap();
+        std::fs::write(parent.join("outside.png"), &png).unwrap();
+        lua.set_app_data(ProjectRoot(root.clone()));
+        lua.set_app_data(SourcePath(root.join("index.rtconfig.txt")));
+        let image: Image = lua.load("load_image('knob.png')").eval().unwrap();
+        assert_eq!(image.pixels, [[1, 2, 3, 255]; 2]);
+        assert!(error("load_image('../outside.png')").contains("outside the root folder"));
+        let tall = Image {
+            width: 1,
+            height: 20000,
+            pixels: vec![[0; 4]; 20000],
+        };
+        std::fs::write(root.join("tall.png"), tall.encode()).unwrap();
+        assert!(error("load_image('tall.png')").contains("too large"));
+    }
+
+    #[test]
+    fn test_generated_resources() {
+        let lua = new_with_options(&InterpreterOptions::default());
+
+        lua.load("resource('a/../b.png', fill_image(1, 1, rgb(1, 2, 3)))")
+            .exec()
+            .unwrap();
+        let generated = take_generated_resources(&lua);
+        assert_eq!(generated.len(), 1);
+        assert_eq!(generated[0].0, RelativePathBuf::from("b.png"));
+        let (image, _) = Image::decode(&generated[0].1.read().unwrap()).unwrap();
+        assert_eq!(image.pixels, [[1, 2, 3, 255]]);
+
+        lua.load(r#"resource_text("info/readme.txt", "hello " .. "theme")"#)
+            .exec()
+            .unwrap();
+        lua.load(r#"resource_text("data.bin", "\xff\x00")"#)
+            .exec()
+            .unwrap();
+        let generated = take_generated_resources(&lua);
+        assert_eq!(
+            generated,
+            vec![
+                (
+                    RelativePathBuf::from("info/readme.txt"),
+                    Resource::Generated(b"hello theme".to_vec())
+                ),
+                (
+                    RelativePathBuf::from("data.bin"),
+                    Resource::Generated(vec![0xff, 0x00])
+                ),
+            ]
+        );
+
+        // calls to resource() belong to the interpreter they were made in
+        let other = new_with_options(&InterpreterOptions::default());
+        lua.load("resource('icons', '*.png')").exec().unwrap();
+        other.load("resource('*.txt')").exec().unwrap();
+        let directives = take_resource_directives(&lua);
+        assert_eq!(directives.len(), 1);
+        assert_eq!(directives[0].dest, RelativePathBuf::from("icons"));
+        assert!(take_resource_directives(&lua).is_empty());
+        assert_eq!(take_resource_directives(&other).len(), 1);
     }
 
     #[test]
@@ -546,7 +2272,7 @@ mod tests {
         // sandbox lua following Roblox's guide:
         // https://luau-lang.org/sandbox
 
-        let lua = new();
+        let lua = new_with_options(&InterpreterOptions::default());
 
         let map_table = lua.create_table().unwrap();
         map_table.set(1, "one").unwrap();
@@ -580,4 +2306,458 @@ mod tests {
 
         dbg!(result);
     }
+
+    #[test]
+    fn test_module_paths() {
+        let paths = |name: &str| {
+            module_paths(name).map(|x| x.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            paths("lib/palette").unwrap(),
+            vec!["lib/palette.lua", "lib/palette/init.lua"]
+        );
+        assert_eq!(
+            paths("lib.palette").unwrap(),
+            vec!["lib/palette.lua", "lib/palette/init.lua"]
+        );
+        assert_eq!(paths("lib/palette.lua").unwrap(), vec!["lib/palette.lua"]);
+        assert_eq!(paths("lib/../x").unwrap(), vec!["x.lua", "x/init.lua"]);
+        for name in ["../x", "lib/../../x", "/etc/x", "C:\\x", "..\\x", ""] {
+            assert!(
+                matches!(paths(name), Err(ModuleError::OutsideRoot(_))),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_require() {
+        let parent = TempDir::new("require");
+        let dir = parent.join("root");
+        std::fs::create_dir_all(dir.join("lib/shapes")).unwrap();
+        std::fs::write(
+            dir.join("lib/palette.lua"),
+            "loads = (loads or 0) + 1\nfunction dim(x) return x // 2 end\nlocal helper = 1",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("lib/shapes/init.lua"),
+            "local palette = require('lib/palette')\nreturn { size = palette.dim(10) }",
+        )
+        .unwrap();
+        std::fs::write(dir.join("lib/broken.lua"), "error('oops')").unwrap();
+        std::fs::write(dir.join("lib/loop.lua"), "require('lib.loop')").unwrap();
+        std::fs::write(parent.join("outside.lua"), "return 1").unwrap();
+
+        let lua = new_with_options(&InterpreterOptions::default());
+        lua.set_app_data(ProjectRoot(dir.clone()));
+        let eval = |code: &str| lua.load(code).eval::<mlua::Value>();
+
+        assert_eq!(
+            eval("return require('lib.palette').dim(9)").unwrap(),
+            mlua::Value::Integer(4)
+        );
+        assert_eq!(
+            eval("return require('lib.shapes').size").unwrap(),
+            mlua::Value::Integer(5)
+        );
+        // modules run once, and don't set shared globals
+        assert_eq!(
+            eval("return require('lib/palette').loads").unwrap(),
+            mlua::Value::Integer(1)
+        );
+        assert_eq!(eval("return dim").unwrap(), mlua::Value::Nil);
+        assert_eq!(
+            eval("return rawequal(require('lib/palette'), require('lib/palette.lua'))").unwrap(),
+            mlua::Value::Boolean(true)
+        );
+
+        let error = |code: &str| eval(code).unwrap_err().to_string();
+        assert!(error("require('../outside')").contains("outside the root folder"));
+        assert!(error("require('lib/missing')").contains("not found"));
+        assert!(error("require('lib/broken')").contains("oops"));
+        assert!(error("require('lib/loop')").contains("being loaded"));
+    }
+
+    #[test]
+    fn test_load_data() {
+        let dir = TempDir::new("load-data");
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(
+            dir.join("data/palette.json"),
+            r#"{ "blue": [0, 0, 255], "name": "dark", "unset": null }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("data/sizes.yaml"),
+            "tcp:\n  height: 24\n  scale: 1.5\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("data/theme.toml"),
+            "name = \"dark\"\nupdated = 2024-01-02\n[colors]\nbg = 0x202020\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("data/broken.json"), "{").unwrap();
+
+        let lua = new_with_options(&InterpreterOptions::default());
+        lua.set_app_data(ProjectRoot(dir.to_path_buf()));
+        lua.set_app_data(SourcePath(dir.join("index.rtconfig.txt")));
+        let eval = |code: &str| lua.load(code).eval::<mlua::Value>();
+
+        let result = eval(
+            r#"
+            local palette = load_json("data/palette.json")
+            return rgb(palette.blue[1], palette.blue[2], palette.blue[3]):arr() == "0 0 255"
+                and palette.name == "dark" and palette.unset == nil
+            "#,
+        );
+        assert_eq!(result.unwrap(), mlua::Value::Boolean(true));
+        let result = eval(r#"return load_yaml("data/sizes.yaml").tcp.height * 2"#);
+        assert_eq!(result.unwrap(), mlua::Value::Integer(48));
+        let result = eval(
+            r#"
+            local theme = load_toml("data/theme.toml")
+            return theme.colors.bg == 0x202020 and theme.updated == "2024-01-02"
+            "#,
+        );
+        assert_eq!(result.unwrap(), mlua::Value::Boolean(true));
+
+        let error = |code: &str| eval(code).unwrap_err().to_string();
+        assert!(error(r#"load_json("data/broken.json")"#).contains("EOF"));
+        assert!(error(r#"load_json("data/missing.json")"#).contains("failed to load"));
+
+        // paths are resolved relative to the file being processed, but must stay in the root
+        lua.set_app_data(SourcePath(dir.join("data/index.rtconfig.txt")));
+        assert_eq!(
+            eval(r#"return load_json("palette.json").name"#).unwrap(),
+            mlua::Value::String(lua.create_string("dark").unwrap())
+        );
+        lua.set_app_data(ProjectRoot(dir.join("data")));
+        std::fs::write(dir.join("sizes.yaml"), "a: 1").unwrap();
+        assert!(error(r#"load_yaml("../sizes.yaml")"#).contains("outside the root folder"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let lua = new_with_options(
+            &InterpreterOptions::default()
+                .instruction_limit(Some(100_000))
+                .memory_limit(Some(16 * 1024 * 1024))
+                .max_depth(Some(50)),
+        );
+        let error = |code: &str| {
+            reset_limits(&lua);
+            lua.load(code).exec().unwrap_err().to_string()
+        };
+
+        assert!(error("while true do end").contains("limit of 100000 instructions"));
+        assert!(error("local s = string.rep('x', 64 * 1024 * 1024)").contains("memory"));
+        assert!(error("local function f(n) return 1 + f(n + 1) end f(1)")
+            .contains("limit of 50 nested function calls"));
+
+        // the count starts again for each expression
+        reset_limits(&lua);
+        lua.load("for i = 1, 5000 do end").exec().unwrap();
+        reset_limits(&lua);
+        lua.load("local function f(n) if n > 0 then return f(n - 1) + 1 end return 0 end f(40)")
+            .exec()
+            .unwrap();
+
+        let lua = new_with_options(
+            &InterpreterOptions::default().timeout(Some(Duration::from_millis(50))),
+        );
+        reset_limits(&lua);
+        let err = lua.load("while true do end").exec().unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+
+        // exceeded limits can't be caught
+        let loop_in_pcall = "while true do pcall(function() while true do end end) end";
+        reset_limits(&lua);
+        let err = lua.load(loop_in_pcall).exec().unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        let lua = new_with_options(&InterpreterOptions::default().instruction_limit(Some(100_000)));
+        for code in [
+            loop_in_pcall,
+            "for i = 1, 3000 do pcall(function() while true do end end) end",
+            "xpcall(function() while true do end end, function(err) return err end)",
+            "coroutine.resume(coroutine.create(function() while true do end end))",
+        ] {
+            reset_limits(&lua);
+            let err = lua.load(code).exec().unwrap_err();
+            assert!(err.to_string().contains("instructions"), "{code}");
+            assert!(check_limits(&lua).is_err());
+        }
+        reset_limits(&lua);
+        assert!(check_limits(&lua).is_ok());
+        let result: (bool, i64) = lua
+            .load("return pcall(function() return 1 end)")
+            .eval()
+            .unwrap();
+        assert_eq!(result, (true, 1));
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let lua = new_with_options(&InterpreterOptions::default());
+        let eval = |lua: &mlua::Lua, code: &str| lua.load(code).eval::<mlua::Value>();
+        let is_nil = |lua: &mlua::Lua, code: &str| {
+            assert_eq!(eval(lua, code).unwrap(), mlua::Value::Nil, "{code}");
+        };
+
+        for code in [
+            "return io",
+            "return os.execute",
+            "return os.exit",
+            "return os.getenv",
+            "return os.remove",
+            "return package",
+            "return debug",
+            "return dofile",
+            "return loadfile",
+            "return string.dump",
+            "return getmetatable('').__index.dump",
+        ] {
+            is_nil(&lua, code);
+        }
+
+        // precompiled chunks are refused, even when asking for binary mode
+        let err = eval(&lua, r#"return load("\27Lua\x54\0", "x", "b")"#).unwrap();
+        assert_eq!(err, mlua::Value::Nil);
+        let result = eval(&lua, r#"local f = load("return 1 + 1") return f()"#);
+        assert_eq!(result.unwrap(), mlua::Value::Integer(2));
+        let result = eval(&lua, r#"answer = 42 return load("return answer")()"#);
+        assert_eq!(result.unwrap(), mlua::Value::Integer(42));
+        let result = eval(
+            &lua,
+            r#"return load("return answer", "x", "t", { answer = 7 })()"#,
+        );
+        assert_eq!(result.unwrap(), mlua::Value::Integer(7));
+
+        // coroutines can't escape the limits
+        let lua = new_with_options(&InterpreterOptions::default().instruction_limit(Some(10_000)));
+        reset_limits(&lua);
+        let err = eval(&lua, "coroutine.wrap(function() while true do end end)()").unwrap_err();
+        assert!(err.to_string().contains("instructions"));
+        reset_limits(&lua);
+        let result = eval(
+            &lua,
+            r#"
+            local gen = coroutine.wrap(function() coroutine.yield(1) coroutine.yield(2) end)
+            local co = coroutine.create(function(a) return a * 2 end)
+            local ok, value = coroutine.resume(co, 20)
+            return gen() + gen() + value
+            "#,
+        );
+        assert_eq!(result.unwrap(), mlua::Value::Integer(43));
+        let err = eval(&lua, "coroutine.wrap(function() error('oops') end)()").unwrap_err();
+        assert!(err.to_string().contains("oops"));
+        let result = eval(
+            &lua,
+            r#"
+            local ok, err = coroutine.resume(coroutine.create(function() error({ code = 1 }) end))
+            local _, wrapped = pcall(coroutine.wrap(function() error({ code = 2 }) end))
+            return (not ok) and err.code + wrapped.code
+            "#,
+        );
+        assert_eq!(result.unwrap(), mlua::Value::Integer(3));
+
+        let lua = new_with_options(
+            &InterpreterOptions::default()
+                .sandbox(SandboxProfile::Strict)
+                .env_allowlist(Some(vec!["REAPER_THEME_PACKER_TEST_ALLOWED".into()])),
+        );
+        for code in [
+            "return load",
+            "return collectgarbage",
+            "return getmetatable('')",
+            "return getmetatable(rgb(1, 2, 3))",
+            "return getmetatable(fill_image(1, 1, rgb(0, 0, 0)))",
+        ] {
+            is_nil(&lua, code);
+        }
+        let result = eval(&lua, "return getmetatable(setmetatable({}, { x = 1 })).x");
+        assert_eq!(result.unwrap(), mlua::Value::Integer(1));
+
+        std::env::set_var("REAPER_THEME_PACKER_TEST_ALLOWED", "yes");
+        let result = eval(&lua, r#"return env("REAPER_THEME_PACKER_TEST_ALLOWED")"#);
+        assert_eq!(
+            result.unwrap(),
+            mlua::Value::String(lua.create_string("yes").unwrap())
+        );
+        let err = eval(&lua, r#"return env("HOME")"#).unwrap_err();
+        assert!(err.to_string().contains("not allowed"));
+    }
+
+    #[test]
+    fn test_build_time() {
+        let options = InterpreterOptions::default()
+            .build_time(Some(1_700_000_000))
+            .random_seed(Some(42));
+        let eval = |lua: &mlua::Lua, code: &str| lua.load(code).eval::<mlua::Value>().unwrap();
+
+        let lua = new_with_options(&options);
+        assert_eq!(
+            eval(&lua, "return BUILD_TIME"),
+            mlua::Value::Integer(1_700_000_000)
+        );
+        assert_eq!(
+            eval(&lua, "return os.time()"),
+            mlua::Value::Integer(1_700_000_000)
+        );
+        assert_eq!(
+            eval(&lua, "return os.date('%Y-%m-%d %H:%M')")
+                .to_string()
+                .unwrap(),
+            "2023-11-14 22:13"
+        );
+        assert_eq!(
+            eval(&lua, "return os.date('!%Y', 0)").to_string().unwrap(),
+            "1970"
+        );
+        assert_eq!(eval(&lua, "return os.clock()"), mlua::Value::Integer(0));
+
+        // date tables are read as UTC, whatever the time zone of the machine is
+        let tz = std::env::var_os("TZ");
+        std::env::set_var("TZ", "EST5");
+        let roundtrip = eval(&lua, "return os.time(os.date('*t', 1700000000))");
+        let time = eval(
+            &lua,
+            "return os.time({ year = 2023, month = 11, day = 14, hour = 22, min = 13, sec = 20 })",
+        );
+        let normalized = eval(
+            &lua,
+            "local t = { year = 2023, month = 13, day = 0 } os.time(t) return t.month .. '-' .. t.day .. ' ' .. t.hour",
+        );
+        match tz {
+            Some(tz) => std::env::set_var("TZ", tz),
+            None => std::env::remove_var("TZ"),
+        }
+        assert_eq!(roundtrip, mlua::Value::Integer(1_700_000_000));
+        assert_eq!(time, mlua::Value::Integer(1_700_000_000));
+        assert_eq!(normalized.to_string().unwrap(), "12-31 12");
+        assert_eq!(
+            eval(
+                &lua,
+                "return os.time({ year = 1969, month = 12, day = 31, hour = 0 })"
+            ),
+            mlua::Value::Integer(-86400)
+        );
+        assert!(lua.load("os.time({ year = 2023 })").exec().is_err());
+
+        // the same seed gives the same numbers, also after reseeding without a seed
+        let numbers = "return math.random(1000000) .. ' ' .. math.random(1000000)";
+        let first = eval(&lua, numbers).to_string().unwrap();
+        let lua = new_with_options(&options);
+        assert_eq!(eval(&lua, numbers).to_string().unwrap(), first);
+        eval(&lua, "math.randomseed()");
+        assert_eq!(eval(&lua, numbers).to_string().unwrap(), first);
+
+        let lua = new_with_options(&InterpreterOptions::default());
+        assert!(
+            matches!(eval(&lua, "return BUILD_TIME"), mlua::Value::Integer(x) if x > 1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn test_color_metamethods() {
+        let lua = new_with_options(&InterpreterOptions::default());
+        let eval = |code: &str| lua.load(code).eval::<mlua::Value>();
+        let string = |code: &str| eval(code).unwrap().to_string().unwrap();
+
+        let result: RGB = lua.load("rgb(10, 20, 30) * 1.5").eval().unwrap();
+        assert_eq!(result, RGB(15, 30, 45));
+        let result: RGB = lua.load("2 * rgb(10, 20, 30)").eval().unwrap();
+        assert_eq!(result, RGB(20, 40, 60));
+        let result: RGBA = lua.load("rgba(10, 20, 30, 40) / 4").eval().unwrap();
+        assert_eq!(result, RGBA(3, 5, 8, 40));
+        assert!(eval("rgb(200, 0, 0) * 2").is_err());
+        assert!(eval("rgb(200, 0, 0) * -1").is_err());
+        assert!(eval("rgb(200, 0, 0) / 0")
+            .unwrap_err()
+            .to_string()
+            .contains("divide a color by zero"));
+
+        assert_eq!(
+            eval("return rgb(1, 2, 3) == rgb(1, 2, 3)").unwrap(),
+            mlua::Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("return rgb(1, 2, 3) == rgb(1, 2, 4)").unwrap(),
+            mlua::Value::Boolean(false)
+        );
+        assert_eq!(
+            eval("return rgb(1, 2, 3) == rgba(1, 2, 3, 0)").unwrap(),
+            mlua::Value::Boolean(false)
+        );
+        assert_eq!(
+            eval("return rgb(1, 2, 3) < rgb(1, 2, 4)").unwrap(),
+            mlua::Value::Boolean(true)
+        );
+        assert_eq!(
+            eval("return rgba(1, 2, 3, 4) >= rgba(1, 2, 3, 4)").unwrap(),
+            mlua::Value::Boolean(true)
+        );
+        assert!(eval("return rgb(1, 2, 3) < rgba(1, 2, 3, 4)")
+            .unwrap_err()
+            .to_string()
+            .contains("different channels"));
+
+        assert_eq!(string("return tostring(rgb(1, 2, 3))"), "rgb(1, 2, 3)");
+        assert_eq!(
+            string("return 'bg: ' .. rgba(1, 2, 3, 4)"),
+            "bg: rgba(1, 2, 3, 4)"
+        );
+        assert_eq!(string("return rgb(1, 2, 3) .. 1"), "rgb(1, 2, 3)1");
+        assert_eq!(
+            string("local c = rgba(1, 2, 3, 4) return c.r + c.g + c.b + c.a"),
+            "10"
+        );
+        assert_eq!(eval("return rgb(1, 2, 3).a").unwrap(), mlua::Value::Nil);
+    }
+
+    #[test]
+    fn test_gradient_palette() {
+        let lua = new_with_options(&InterpreterOptions::default());
+        let colors = |code: &str| lua.load(code).eval::<Vec<mlua::Value>>();
+
+        let result: Vec<RGB> = lua
+            .load("gradient(rgb(0, 0, 0), rgb(255, 255, 255), 3)")
+            .eval()
+            .unwrap();
+        assert_eq!(
+            result,
+            vec![RGB(0, 0, 0), RGB(128, 128, 128), RGB(255, 255, 255)]
+        );
+        let result: Vec<RGB> = lua
+            .load("gradient(rgb(255, 0, 0), rgb(0, 0, 255), 3, 'hsv')")
+            .eval()
+            .unwrap();
+        assert_eq!(result[1], RGB(255, 0, 255));
+        let result: Vec<RGBA> = lua
+            .load("gradient(rgb(0, 0, 0), rgba(0, 0, 0, 55), 2, 'oklab')")
+            .eval()
+            .unwrap();
+        assert_eq!(result, vec![RGBA(0, 0, 0, 255), RGBA(0, 0, 0, 55)]);
+        assert_eq!(
+            colors("gradient(rgb(0, 0, 0), rgb(1, 1, 1), 0)")
+                .unwrap()
+                .len(),
+            0
+        );
+        assert!(colors("gradient(rgb(0, 0, 0), rgb(1, 1, 1), 2, 'lab')")
+            .unwrap_err()
+            .to_string()
+            .contains("unknown color space"));
+
+        let result: Vec<RGB> = lua
+            .load("palette(rgb(200, 60, 60), {hues = 3, lightness = {-0.1, 0, 0.1}})")
+            .eval()
+            .unwrap();
+        assert_eq!(result.len(), 9);
+        assert_eq!(result[1], RGB(200, 60, 60));
+        assert_ne!(result[4], result[1]);
+        let result: Vec<RGBA> = lua.load("palette(rgba(1, 2, 3, 4))").eval().unwrap();
+        assert_eq!(result, vec![RGBA(1, 2, 3, 4)]);
+    }
 }
@@ -1,13 +1,31 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::Duration,
+};
 
-use clap::Parser;
-use log::error;
-use theme::BuildOptions;
+use cache::Cache;
+use clap::{Args, Parser, Subcommand};
+use diagnostics::{Diagnostic, MessageFormat};
+use interpreter::{InterpreterOptions, SandboxProfile};
+use log::info;
+use preprocess::{EmptyGlobPolicy, PreprocessOptions, PreprocessOutput};
+use theme::{BuildOptions, Compression, Resource};
 
+mod cache;
+mod diagnostics;
+mod images;
 mod interpreter;
+mod lsp;
+mod palette;
 mod parser;
 mod preprocess;
+mod references;
+mod scaffold;
+#[cfg(test)]
+mod testing;
 mod theme;
+mod theme_keys;
 
 pub fn setup_logging() {
     use env_logger::Env;
@@ -18,26 +36,196 @@ pub fn setup_logging() {
 }
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct MainArgs {
-    input: PathBuf,
-    output: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[clap(required = true)]
+    input: Option<PathBuf>,
+    #[clap(required = true)]
+    output: Option<PathBuf>,
     #[clap(long, short, action)]
     overwrite: bool,
     #[clap(long, short, action)]
-    /// Write extra .rtconfig.txt, .ReaperTheme, .res.json files alongside the output ZIP
+    /// Write extra .rtconfig.txt, .ReaperTheme, .res.json, .rtconfig.map.json files alongside the output ZIP
     debug: bool,
+    #[clap(long, action)]
+    /// Treat resource patterns that don't match any files as errors instead of warnings
+    strict: bool,
+    #[clap(long, value_enum, default_value_t = MessageFormat::Human)]
+    /// Format of errors and warnings; `json` prints one object per line to stdout
+    message_format: MessageFormat,
+    #[clap(long, action)]
+    /// Report images referenced by the rtconfig but not packed
+    check_references: bool,
+    #[clap(long, action, requires = "check_references")]
+    /// With --check-references, also report packed images that the rtconfig never seems to use.
+    /// Standard images are only known for the TCP/MCP mute, solo, phase, record arm and record
+    /// monitor buttons; these and images starting with a common prefix like `tcp_`, `mcp_` or
+    /// `gen_` are never reported
+    report_unused_images: bool,
+    #[clap(long, action)]
+    /// Skip checking PNG resources for invalid files, pink pixel borders and scaled image sizes
+    no_validate_images: bool,
+    #[clap(long, action)]
+    /// Generate missing 150% and 200% copies of PNG resources by scaling the 100% images
+    hidpi: bool,
+    #[clap(long, action)]
+    /// Losslessly recompress PNG resources, storing images with at most 256 colors as an 8-bit
+    /// palette, and report the size saved for each file and in total
+    optimize_png: bool,
+    #[clap(long, value_enum)]
+    /// Compression method of every file in the ZIP; by default PNG images are stored as is and
+    /// other files are deflated
+    compression: Option<Compression>,
+    #[clap(long, value_parser = clap::value_parser!(i32).range(0..=9))]
+    /// Deflate level of deflated files in the ZIP; by default text files use 9 and other files use 6
+    compression_level: Option<i32>,
+    #[clap(long, short)]
+    /// Number of threads compressing resources; defaults to the number of CPUs
+    jobs: Option<usize>,
+    #[clap(long)]
+    /// Folder to cache preprocessor output and compressed resources in, to skip work on rebuilds.
+    /// Each output file gets its own subfolder, so the folder can be shared by several themes
+    cache: Option<PathBuf>,
+    #[clap(long, value_name = "N")]
+    /// Maximum number of Lua instructions each expression or script may run; 0 for no limit
+    lua_instruction_limit: Option<u64>,
+    #[clap(long, value_name = "MB", default_value = "1024", value_parser = parse_megabytes)]
+    /// Maximum memory used by the Lua interpreter in megabytes; 0 for no limit
+    lua_memory_limit: usize,
+    #[clap(long, value_name = "SECONDS", default_value = "60", value_parser = parse_seconds)]
+    /// Maximum time each Lua expression or script may run; 0 for no limit
+    lua_timeout: Duration,
+    #[clap(long, value_name = "N", default_value_t = 500)]
+    /// Maximum depth of nested Lua function calls; 0 for no limit
+    lua_max_depth: usize,
+    #[clap(long, value_enum, default_value_t = SandboxProfile::Standard)]
+    /// Which Lua functions are available; `strict` also removes `load`, `collectgarbage` and
+    /// access to metatables other than those of tables
+    sandbox: SandboxProfile,
+    #[clap(long, value_name = "NAME")]
+    /// Environment variable that `env()` may read; may be given multiple times. When given,
+    /// other variables can't be read. With `--sandbox strict`, only these variables can be read.
+    allow_env: Vec<String>,
+    #[clap(long, value_name = "UNIX_SECONDS")]
+    /// Build reproducibly: `os.time()`, `os.date()` and the times of files in the ZIP use this time
+    /// instead of the current time. Defaults to the `SOURCE_DATE_EPOCH` environment variable.
+    build_time: Option<i64>,
+    #[clap(long, value_name = "N")]
+    /// Seed of `math.random`; defaults to 0 when building reproducibly, and a random seed otherwise
+    random_seed: Option<i64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a new theme project with an rtconfig, colors, image folders and a theme.toml manifest
+    Init(InitArgs),
+}
+
+#[derive(Args, Debug)]
+struct InitArgs {
+    #[clap(default_value = ".")]
+    /// Folder to create the project in; created if it doesn't exist
+    dir: PathBuf,
+    #[clap(long)]
+    /// Name of the theme; defaults to the name of the folder
+    name: Option<String>,
+}
+
+fn init_main(args: InitArgs) {
+    let name = match &args.name {
+        Some(x) => x.clone(),
+        None => match std::path::absolute(&args.dir)
+            .ok()
+            .and_then(|x| x.file_name().map(|x| x.to_string_lossy().to_string()))
+        {
+            Some(x) => x,
+            None => {
+                return Diagnostic::error("invalid-name", "folder does not have a name, use --name")
+                    .file(&args.dir)
+                    .emit()
+            }
+        },
+    };
+
+    match scaffold::init(&args.dir, &name) {
+        Ok(files) => {
+            for file in files {
+                Diagnostic::note("file-created", "created")
+                    .file(&file)
+                    .emit();
+            }
+            Diagnostic::note(
+                "init-done",
+                format!(
+                    "build the theme with: reaper-theme-packer {} {}.ReaperThemeZip",
+                    args.dir.join("index.rtconfig.txt").display(),
+                    name
+                ),
+            )
+            .emit();
+        }
+        Err(err) => Diagnostic::from(&err).emit(),
+    }
+}
+
+/// Parse a number of megabytes into bytes
+fn parse_megabytes(arg: &str) -> Result<usize, String> {
+    let megabytes: usize = arg.parse().map_err(|err| format!("{err}"))?;
+    megabytes
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| format!("{megabytes} MB is too large"))
+}
+
+/// Parse a non-negative number of seconds
+fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    let seconds: f64 = arg.parse().map_err(|err| format!("{err}"))?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| format!("{arg} is not a valid number of seconds"))
+}
+
+/// Entry point of the language server binary
+pub fn lsp_main() {
+    setup_logging();
+
+    if let Err(err) = lsp::run() {
+        Diagnostic::error("lsp-error", err.to_string()).emit();
+    }
 }
 
 pub fn main() {
     setup_logging();
 
     let args: MainArgs = MainArgs::parse();
+    diagnostics::set_message_format(args.message_format);
 
-    let theme_name = match args.output.file_stem() {
-        None => return error!("output file does not have a name"),
+    if let Some(Command::Init(init_args)) = args.command {
+        return init_main(init_args);
+    }
+    // clap requires both when there's no subcommand
+    let (Some(input), Some(output_path)) = (&args.input, &args.output) else {
+        unreachable!()
+    };
+
+    let theme_name = match output_path.file_stem() {
+        None => {
+            return Diagnostic::error("invalid-output", "output file does not have a name")
+                .file(output_path)
+                .emit()
+        }
         Some(stem) => match stem.to_str() {
-            None => return error!("output file name is not valid UTF8"),
+            None => {
+                return Diagnostic::error("invalid-output", "output file name is not valid UTF8")
+                    .file(output_path)
+                    .emit()
+            }
             Some(x) => x,
         },
     };
@@ -47,26 +235,116 @@ pub fn main() {
         map.insert("THEME_NAME".into(), theme_name.to_string());
         map
     };
-    let (rtconfig, reapertheme, resources) =
-        match preprocess::preprocess(&args.input, Some(globals)) {
-            Ok(x) => x,
-            Err(err) => return error!("{}", err),
-        };
+    let build_time = match args.build_time {
+        Some(x) => Some(x),
+        None => match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(x) => match x.trim().parse::<i64>() {
+                Ok(x) => Some(x),
+                Err(_) => {
+                    return Diagnostic::error(
+                        "invalid-source-date-epoch",
+                        format!("SOURCE_DATE_EPOCH `{}` is not a number of seconds", x),
+                    )
+                    .emit()
+                }
+            },
+            Err(_) => None,
+        },
+    };
+    let random_seed = args.random_seed.or(build_time.map(|_| 0));
+
+    let preprocess_options = PreprocessOptions::default()
+        .empty_glob(if args.strict {
+            EmptyGlobPolicy::Error
+        } else {
+            EmptyGlobPolicy::Warn
+        })
+        .interpreter(
+            InterpreterOptions::default()
+                .instruction_limit(args.lua_instruction_limit.filter(|x| *x > 0))
+                .memory_limit(Some(args.lua_memory_limit).filter(|x| *x > 0))
+                .timeout(Some(args.lua_timeout).filter(|x| !x.is_zero()))
+                .max_depth(Some(args.lua_max_depth).filter(|x| *x > 0))
+                .sandbox(args.sandbox)
+                .env_allowlist(Some(args.allow_env.clone()).filter(|x| !x.is_empty()))
+                .build_time(build_time)
+                .random_seed(random_seed),
+        );
+    let cache = args
+        .cache
+        .as_deref()
+        .map(|dir| Cache::for_output(dir, output_path));
+    // without a build time, `BUILD_TIME`, the time functions and `math.random` change between
+    // runs, so the preprocessor output can't be reused
+    let preprocess_cache = cache.as_ref().filter(|_| build_time.is_some());
+    if cache.is_some() && preprocess_cache.is_none() {
+        info!("no build time is set, so the preprocessor output isn't cached");
+    }
+    let cache_key = cache::hash_parts(&[
+        env!("CARGO_PKG_VERSION"),
+        input.to_string_lossy().as_ref(),
+        format!("{:?}", globals.iter().collect::<BTreeMap<_, _>>()).as_str(),
+        format!("{:?}", preprocess_options).as_str(),
+    ]);
+    let cached = preprocess_cache.and_then(|x| x.load_preprocess(&cache_key));
+    let output = match cached {
+        Some(output) => {
+            info!("inputs are unchanged, using cached preprocessor output");
+            for diagnostic in &output.diagnostics {
+                diagnostic.emit();
+            }
+            output
+        }
+        None => {
+            let output = match preprocess::preprocess(input, Some(globals), &preprocess_options) {
+                Ok(x) => x,
+                Err(err) => return Diagnostic::from(&err).emit(),
+            };
+            if let Some(cache) = preprocess_cache {
+                if let Err(err) = cache.save_preprocess(&cache_key, &output) {
+                    Diagnostic::warning(
+                        "cache-write",
+                        format!("failed to write preprocessor output to cache: {}", err),
+                    )
+                    .file(cache.dir())
+                    .emit();
+                }
+            }
+            output
+        }
+    };
+    let PreprocessOutput {
+        rtconfig,
+        reapertheme,
+        mut resources,
+        source_map,
+        ..
+    } = output;
+
+    if args.hidpi {
+        for diagnostic in images::generate_scaled_images(&mut resources) {
+            diagnostic.emit();
+        }
+    }
 
     if args.debug {
         // write rtconfig
-        let rtconfig_path = args.output.with_extension("rtconfig.txt");
+        let rtconfig_path = output_path.with_extension("rtconfig.txt");
         std::fs::write(rtconfig_path, &rtconfig).unwrap();
 
         // write reapertheme
-        let reapertheme_path = args.output.with_extension("ReaperTheme");
+        let reapertheme_path = output_path.with_extension("ReaperTheme");
         reapertheme.write_to_file(reapertheme_path).unwrap();
 
-        let resources_path = args.output.with_extension("res.json");
+        let resources_path = output_path.with_extension("res.json");
         let new_resources = {
             let mut result: HashMap<String, String> = HashMap::new();
             for (k, v) in resources.iter() {
-                result.insert(k.to_string(), v.to_string_lossy().to_string());
+                let source = match v {
+                    Resource::File(path) => path.to_string_lossy().to_string(),
+                    Resource::Generated(bytes) => format!("<generated, {} bytes>", bytes.len()),
+                };
+                result.insert(k.to_string(), source);
             }
             result
         };
@@ -75,14 +353,65 @@ pub fn main() {
             serde_json::to_string_pretty(&new_resources).unwrap(),
         )
         .unwrap();
+
+        // write source map, one entry per line of the rtconfig
+        let source_map_path = output_path.with_extension("rtconfig.map.json");
+        let source_map: Vec<_> = source_map
+            .iter()
+            .enumerate()
+            .map(|(i, loc)| {
+                serde_json::json!({
+                    "output_line": i + 1,
+                    "file": loc.file.to_string_lossy(),
+                    "line": loc.line,
+                })
+            })
+            .collect();
+        std::fs::write(
+            source_map_path,
+            serde_json::to_string_pretty(&source_map).unwrap(),
+        )
+        .unwrap();
+    }
+
+    if args.check_references {
+        for diagnostic in references::check(&rtconfig, &resources, args.report_unused_images) {
+            diagnostic.emit();
+        }
     }
 
     let theme = theme::Theme::new(theme_name, &rtconfig, reapertheme, resources);
-    match theme.build(
-        &args.output,
-        &BuildOptions::default().overwrite(args.overwrite),
+    if let Err(err) = theme.build(
+        output_path,
+        &BuildOptions::default()
+            .overwrite(args.overwrite)
+            .validate_images(!args.no_validate_images)
+            .optimize_png(args.optimize_png)
+            .compression(args.compression)
+            .compression_level(args.compression_level)
+            .jobs(args.jobs)
+            .cache(cache)
+            .build_time(build_time),
     ) {
-        Err(err) => return error!("{}", err),
-        _ => (),
+        Diagnostic::from(&err).emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limits() {
+        assert_eq!(parse_megabytes("2"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_megabytes("0"), Ok(0));
+        assert!(parse_megabytes(&usize::MAX.to_string()).is_err());
+        assert!(parse_megabytes("-1").is_err());
+
+        assert_eq!(parse_seconds("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_seconds("0"), Ok(Duration::ZERO));
+        for arg in ["-1", "NaN", "inf", "1e30", "abc"] {
+            assert!(parse_seconds(arg).is_err(), "{arg}");
+        }
     }
 }
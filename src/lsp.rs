@@ -0,0 +1,1107 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+        Notification as _, PublishDiagnostics,
+    },
+    request::{
+        ColorPresentationRequest, Completion, DocumentColor, GotoDefinition, HoverRequest,
+        Request as _,
+    },
+    Color, ColorInformation, ColorPresentation, CompletionItem, CompletionItemKind,
+    CompletionOptions, CompletionResponse, Diagnostic, DiagnosticSeverity, GotoDefinitionResponse,
+    Hover, HoverContents, HoverProviderCapability, InitializeParams, InsertTextFormat, Location,
+    MarkupContent, MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range,
+    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Url,
+};
+use relative_path::RelativePathBuf;
+
+use crate::{
+    interpreter::{self, Blend, InterpreterOptions, Toggle, RGB, RGBA},
+    parser::{self, Directive, Input, RtconfigContent},
+};
+
+type Result<T = ()> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// How long to wait after the last change before checking the changed documents again, so typing
+/// doesn't evaluate every keystroke
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Directives offered as completions after a `#` at the start of a line
+const DIRECTIVES: [(&str, &str, &str); 2] = [
+    (
+        "include",
+        "include \"$1\"",
+        "Include a .rtconfig.txt, .ini or .lua file relative to this file",
+    ),
+    (
+        "resource",
+        "resource \"$1\"",
+        "Add resources matching a glob pattern to the theme",
+    ),
+];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum DocumentKind {
+    RtConfig,
+    ReaperTheme,
+    Lua,
+}
+
+impl DocumentKind {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().map(|x| x.to_ascii_lowercase()) {
+            Some(ext) => match ext.to_string_lossy().as_ref() {
+                "reapertheme" | "ini" => Self::ReaperTheme,
+                "lua" => Self::Lua,
+                _ => Self::RtConfig,
+            },
+            None => Self::RtConfig,
+        }
+    }
+}
+
+/// A `#{...}` expression found in a document
+#[derive(Debug, PartialEq)]
+struct Expression {
+    code: String,
+    /// Range of the code between the braces
+    range: Range,
+}
+
+fn position_of(input: &Input) -> Position {
+    Position::new(
+        input.location_line() - 1,
+        (input.get_utf8_column() - 1) as u32,
+    )
+}
+
+/// Find the position after the end of `text`, given that it starts at `start`
+fn end_position(start: Position, text: &str) -> Position {
+    match text.rsplit_once('\n') {
+        None => Position::new(start.line, start.character + text.chars().count() as u32),
+        Some((before, after)) => Position::new(
+            start.line + before.matches('\n').count() as u32 + 1,
+            after.chars().count() as u32,
+        ),
+    }
+}
+
+fn span_range(input: &Input) -> Range {
+    let start = position_of(input);
+    Range::new(start, end_position(start, input.fragment()))
+}
+
+fn contains(range: &Range, position: &Position) -> bool {
+    let after_start =
+        (position.line, position.character) >= (range.start.line, range.start.character);
+    let before_end = (position.line, position.character) <= (range.end.line, range.end.character);
+    after_start && before_end
+}
+
+/// Convert a position with the column in characters to one in UTF-16 code units, as used by the
+/// protocol
+fn to_utf16(text: &str, position: Position) -> Position {
+    let line = text.lines().nth(position.line as usize).unwrap_or("");
+    let character = line
+        .chars()
+        .take(position.character as usize)
+        .map(|x| x.len_utf16() as u32)
+        .sum();
+    Position::new(position.line, character)
+}
+
+/// Convert a position with the column in UTF-16 code units to one in characters
+fn from_utf16(text: &str, position: Position) -> Position {
+    let line = text.lines().nth(position.line as usize).unwrap_or("");
+    let mut units = 0;
+    let character = line
+        .chars()
+        .take_while(|x| {
+            units += x.len_utf16() as u32;
+            units <= position.character
+        })
+        .count();
+    Position::new(position.line, character as u32)
+}
+
+fn range_to_utf16(text: &str, range: Range) -> Range {
+    Range::new(to_utf16(text, range.start), to_utf16(text, range.end))
+}
+
+fn parse_error_diagnostic(err: &parser::ParseError) -> Diagnostic {
+    let location = err.location();
+    let start = Position::new(
+        location.line.saturating_sub(1),
+        location.column_utf8.saturating_sub(1) as u32,
+    );
+    let end = end_position(start, location.fragment.lines().next().unwrap_or(""));
+    diagnostic(Range::new(start, end), err.to_string())
+}
+
+fn diagnostic(range: Range, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("reaper-theme-packer".into()),
+        message,
+        ..Default::default()
+    }
+}
+
+/// Find all expressions in a .ReaperTheme/.ini document. Each value is parsed individually like
+/// the preprocessor does, so the column of each value is added back onto the expression.
+fn reapertheme_expressions(text: &str) -> (Vec<Expression>, Vec<Diagnostic>) {
+    let mut expressions = Vec::new();
+    let mut diagnostics = Vec::new();
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('[') || trimmed.starts_with(';') || trimmed.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value_column = key.chars().count() as u32 + 1;
+
+        match parser::parse_reapertheme(value) {
+            Ok(contents) => {
+                for content in contents {
+                    if let parser::ReaperThemeContent::Expression(expr) = content {
+                        let mut range = span_range(&expr);
+                        range.start.line = line_idx as u32;
+                        range.start.character += value_column;
+                        range.end.line = line_idx as u32;
+                        range.end.character += value_column;
+                        expressions.push(Expression {
+                            code: expr.fragment().to_string(),
+                            range,
+                        });
+                    }
+                }
+            }
+            Err(err) => {
+                let mut diag = parse_error_diagnostic(&err);
+                diag.range.start.line = line_idx as u32;
+                diag.range.start.character += value_column;
+                diag.range.end.line = line_idx as u32;
+                diag.range.end.character += value_column;
+                diagnostics.push(diag);
+            }
+        }
+    }
+
+    (expressions, diagnostics)
+}
+
+/// Find all expressions in a .rtconfig.txt document
+fn rtconfig_expressions(text: &str) -> (Vec<Expression>, Vec<Diagnostic>) {
+    match parser::parse_rtconfig(text) {
+        Ok(contents) => {
+            let expressions = contents
+                .iter()
+                .filter_map(|x| match x {
+                    RtconfigContent::Expression(expr) => Some(Expression {
+                        code: expr.fragment().to_string(),
+                        range: span_range(expr),
+                    }),
+                    _ => None,
+                })
+                .collect();
+            (expressions, vec![])
+        }
+        Err(err) => (vec![], vec![parse_error_diagnostic(&err)]),
+    }
+}
+
+/// Get the path in an `#include "..."` directive on the given line
+fn include_on_line(line: &str) -> Option<RelativePathBuf> {
+    let rest = line.trim_start().strip_prefix("#include")?;
+    let rest = rest.trim_start().strip_prefix('"')?;
+    let (path, _) = rest.split_once('"')?;
+    Some(RelativePathBuf::from(path).normalize())
+}
+
+/// Get the 0-based line of an error raised in the file with the given name, from a Lua error
+/// message like `.../colors.lua:3: boom`
+fn error_line(message: &str, file_name: &str) -> Option<u32> {
+    let pattern = format!("{file_name}:");
+    message.match_indices(&pattern).find_map(|(i, _)| {
+        let rest = &message[i + pattern.len()..];
+        let digits = rest.split(':').next()?;
+        digits.parse::<u32>().ok()?.checked_sub(1)
+    })
+}
+
+/// Get the Lua global at the given column, e.g. `my_colors` when the cursor is on
+/// `my_colors.blue`
+fn global_at(line: &str, character: usize) -> Option<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let is_chain = |c: char| is_ident(c) || c == '.' || c == ':';
+
+    let mut start = character.min(chars.len());
+    while start > 0 && is_chain(chars[start - 1]) {
+        start -= 1;
+    }
+    let name: String = chars[start..]
+        .iter()
+        .take_while(|c| is_ident(**c))
+        .collect();
+
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Find where a global is defined in a Lua script: either `function name(...)` or a top-level
+/// `name = ...` assignment
+fn find_lua_definition(script: &str, name: &str) -> Option<Range> {
+    for (line_idx, line) in script.lines().enumerate() {
+        let trimmed = line.trim_start();
+
+        let found = if let Some(rest) = trimmed.strip_prefix("function ") {
+            let rest = rest.trim_start();
+            rest.strip_prefix(name)
+                .is_some_and(|x| x.trim_start().starts_with(['(', '.', ':']))
+        } else if trimmed.len() == line.len() {
+            // only unindented assignments, indented ones are likely table fields
+            line.strip_prefix(name).is_some_and(|x| {
+                let x = x.trim_start();
+                x.starts_with('=') && !x.starts_with("==")
+            })
+        } else {
+            false
+        };
+
+        if found {
+            let column = line.find(name).unwrap_or(0);
+            let column = line[..column].chars().count() as u32;
+            return Some(Range::new(
+                Position::new(line_idx as u32, column),
+                Position::new(line_idx as u32, column + name.chars().count() as u32),
+            ));
+        }
+    }
+    None
+}
+
+fn describe_value(value: &mlua::Value) -> (String, Option<Color>) {
+    match value {
+        mlua::Value::Nil => ("`nil` (empty)".into(), None),
+        mlua::Value::Boolean(x) => (format!("`{x}`"), None),
+        mlua::Value::Integer(x) => (format!("`{x}`"), None),
+        mlua::Value::Number(x) => (format!("`{x}`"), None),
+        mlua::Value::String(x) => (format!("```\n{}\n```", x.to_string_lossy()), None),
+        mlua::Value::UserData(userdata) => {
+            if let Ok(color) = userdata.borrow::<RGB>() {
+                let value = color.value();
+                let (r, g, b) = (value >> 16 & 0xff, value >> 8 & 0xff, value & 0xff);
+                (
+                    format!(
+                        "`rgb({r}, {g}, {b})` `#{:06X}`\n\nSerialised: `{}`",
+                        value,
+                        color.value_rev()
+                    ),
+                    Some(Color {
+                        red: r as f32 / 255.0,
+                        green: g as f32 / 255.0,
+                        blue: b as f32 / 255.0,
+                        alpha: 1.0,
+                    }),
+                )
+            } else if let Ok(color) = userdata.borrow::<RGBA>() {
+                let value = color.value();
+                let (r, g, b, a) = (
+                    value >> 24 & 0xff,
+                    value >> 16 & 0xff,
+                    value >> 8 & 0xff,
+                    value & 0xff,
+                );
+                (
+                    format!(
+                        "`rgba({r}, {g}, {b}, {a})` `#{:08X}`\n\nSerialised: `{}`",
+                        value,
+                        color.value_rev()
+                    ),
+                    Some(Color {
+                        red: r as f32 / 255.0,
+                        green: g as f32 / 255.0,
+                        blue: b as f32 / 255.0,
+                        alpha: a as f32 / 255.0,
+                    }),
+                )
+            } else if let Ok(blend) = userdata.borrow::<Blend>() {
+                (
+                    format!("`{}`\n\nSerialised: `{}`", blend, blend.value()),
+                    None,
+                )
+            } else if let Ok(toggle) = userdata.borrow::<Toggle>() {
+                (
+                    format!("`{}`\n\nSerialised: `{}`", toggle, toggle.value()),
+                    None,
+                )
+            } else {
+                ("userdata".into(), None)
+            }
+        }
+        x => (format!("`{}`", x.type_name()), None),
+    }
+}
+
+/// Paths of Lua scripts with their contents
+type Scripts = Vec<(PathBuf, String)>;
+
+/// Error messages of Lua scripts that failed to run, by path
+type ScriptErrors = HashMap<PathBuf, String>;
+
+struct Server {
+    connection: Connection,
+    root: Option<PathBuf>,
+    documents: HashMap<Url, String>,
+    /// Documents changed since diagnostics were last published
+    dirty: HashSet<Url>,
+    /// The last interpreter created, with the scripts it ran, their contents and the errors they
+    /// raised. Scripts only run again when one of them changes.
+    interpreter: RefCell<Option<(Scripts, mlua::Lua, ScriptErrors)>>,
+}
+
+impl Server {
+    fn new(connection: Connection, params: InitializeParams) -> Self {
+        #[allow(deprecated)]
+        let root = params
+            .root_uri
+            .and_then(|x| x.to_file_path().ok())
+            .or(params.root_path.map(PathBuf::from));
+
+        // an explicit entry file may be given in the initialization options, otherwise use
+        // `index.rtconfig.txt` in the workspace root
+        let entry = params
+            .initialization_options
+            .as_ref()
+            .and_then(|x| x.get("root"))
+            .and_then(|x| x.as_str())
+            .map(PathBuf::from)
+            .or(Some("index.rtconfig.txt".into()));
+        let root = match (root, entry) {
+            (Some(root), Some(entry)) => Some(root.join(entry)).filter(|x| x.is_file()),
+            _ => None,
+        };
+
+        Self {
+            connection,
+            root,
+            documents: HashMap::new(),
+            dirty: HashSet::new(),
+            interpreter: RefCell::new(None),
+        }
+    }
+
+    /// Read a file, preferring the unsaved contents if it is open in the editor
+    fn read(&self, path: &Path) -> Option<String> {
+        let open = Url::from_file_path(path)
+            .ok()
+            .and_then(|x| self.documents.get(&x).cloned());
+        open.or_else(|| std::fs::read_to_string(path).ok())
+    }
+
+    /// Collect the Lua scripts included by a rtconfig file, following nested rtconfig includes
+    fn collect_scripts(&self, path: &Path, visited: &mut HashSet<PathBuf>, out: &mut Vec<PathBuf>) {
+        if !visited.insert(path.to_path_buf()) {
+            return;
+        }
+        let Some(text) = self.read(path) else {
+            return;
+        };
+        let Ok(contents) = parser::parse_rtconfig(&text) else {
+            return;
+        };
+        let parent = path.parent().unwrap_or(Path::new("."));
+
+        for content in contents {
+            if let RtconfigContent::Directive(Directive::Include(relpath)) = content {
+                let include_path = relpath.to_path(parent);
+                match DocumentKind::from_path(&include_path) {
+                    DocumentKind::Lua => {
+                        if !out.contains(&include_path) {
+                            out.push(include_path)
+                        }
+                    }
+                    DocumentKind::RtConfig => self.collect_scripts(&include_path, visited, out),
+                    DocumentKind::ReaperTheme => (),
+                }
+            }
+        }
+    }
+
+    /// Lua scripts that are visible from the given document: those included from the workspace
+    /// entry file, followed by those included by the document itself
+    fn scripts(&self, path: &Path) -> Vec<PathBuf> {
+        let mut visited = HashSet::new();
+        let mut scripts = Vec::new();
+        if let Some(root) = &self.root {
+            self.collect_scripts(root, &mut visited, &mut scripts);
+        }
+        if DocumentKind::from_path(path) == DocumentKind::RtConfig {
+            self.collect_scripts(path, &mut visited, &mut scripts);
+        }
+        scripts
+    }
+
+    /// Get an interpreter with all visible scripts executed, and the errors raised by the scripts.
+    /// The interpreter is reused as long as the scripts are unchanged, so evaluate code in a fresh
+    /// environment from [`Self::environment`] instead of the globals.
+    fn interpreter(&self, path: &Path) -> (mlua::Lua, ScriptErrors) {
+        let scripts: Scripts = self
+            .scripts(path)
+            .into_iter()
+            .filter_map(|x| self.read(&x).map(|script| (x, script)))
+            .collect();
+
+        let mut cache = self.interpreter.borrow_mut();
+        let (lua, errors) = match cache.as_ref() {
+            Some((cached, lua, errors)) if *cached == scripts => (lua.clone(), errors.clone()),
+            _ => {
+                let (lua, errors) = self.run_scripts(&scripts);
+                *cache = Some((scripts, lua.clone(), errors.clone()));
+                (lua, errors)
+            }
+        };
+        lua.set_app_data(interpreter::SourcePath(path.to_path_buf()));
+        (lua, errors)
+    }
+
+    /// A new environment for one pass of evaluating expressions. Globals set by expressions are
+    /// kept in the environment, so they don't leak into later passes, while globals from the
+    /// scripts are still visible.
+    fn environment(lua: &mlua::Lua) -> mlua::Table {
+        let env = lua.create_table().unwrap();
+        let metatable = lua.create_table().unwrap();
+        metatable.set("__index", lua.globals()).unwrap();
+        env.set_metatable(Some(metatable));
+        env
+    }
+
+    fn run_scripts(&self, scripts: &[(PathBuf, String)]) -> (mlua::Lua, ScriptErrors) {
+        // evaluating is interactive, so give up on slow code sooner than when building
+        let lua = interpreter::new_with_options(
+            &InterpreterOptions::default().timeout(Some(Duration::from_secs(5))),
+        );
+        let theme_name = self
+            .root
+            .as_ref()
+            .and_then(|x| x.parent())
+            .and_then(|x| x.file_name())
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or("theme".into());
+        lua.globals().set("THEME_NAME", theme_name).unwrap();
+        if let Some(root) = self.root.as_ref().and_then(|x| x.parent()) {
+            lua.set_app_data(interpreter::ProjectRoot(root.to_path_buf()));
+        }
+
+        let mut errors = ScriptErrors::new();
+        for (script_path, script) in scripts {
+            lua.set_app_data(interpreter::SourcePath(script_path.clone()));
+            interpreter::reset_limits(&lua);
+            // `@` names the chunk after a file, so long paths are shortened from the start
+            let result = lua
+                .load(script.as_str())
+                .set_name(format!("@{}", script_path.to_string_lossy()))
+                .exec()
+                .and_then(|_| interpreter::check_limits(&lua));
+            if let Err(err) = result {
+                errors.insert(script_path.clone(), err.to_string());
+            }
+        }
+
+        (lua, errors)
+    }
+
+    /// Errors in a Lua document. Scripts included by the workspace report the error raised when
+    /// running them, other scripts are only checked for syntax errors.
+    fn script_diagnostics(&self, path: &Path, text: &str) -> Vec<Diagnostic> {
+        let (lua, errors) = self.interpreter(path);
+        let included = self.scripts(path).iter().any(|x| x == path);
+        let message = match included {
+            true => errors.get(path).cloned(),
+            false => lua
+                .load(text)
+                .set_name(format!("@{}", path.to_string_lossy()))
+                .into_function()
+                .err()
+                .map(|x| x.to_string()),
+        };
+        let Some(message) = message else {
+            return vec![];
+        };
+
+        let line = path
+            .file_name()
+            .and_then(|x| error_line(&message, &x.to_string_lossy()))
+            .unwrap_or(0);
+        let length = text
+            .lines()
+            .nth(line as usize)
+            .map_or(0, |x| x.chars().count());
+        let range = Range::new(Position::new(line, 0), Position::new(line, length as u32));
+        vec![diagnostic(range, message)]
+    }
+
+    fn expressions(&self, path: &Path, text: &str) -> (Vec<Expression>, Vec<Diagnostic>) {
+        match DocumentKind::from_path(path) {
+            DocumentKind::RtConfig => rtconfig_expressions(text),
+            DocumentKind::ReaperTheme => reapertheme_expressions(text),
+            DocumentKind::Lua => (vec![], vec![]),
+        }
+    }
+
+    fn evaluate(
+        lua: &mlua::Lua,
+        env: &mlua::Table,
+        expr: &Expression,
+    ) -> mlua::Result<mlua::Value> {
+        interpreter::reset_limits(lua);
+        let result = lua
+            .load(expr.code.as_str())
+            .set_mode(mlua::ChunkMode::Text)
+            .set_name(expr.code.as_str())
+            .set_environment(env.clone())
+            .eval()
+            .and_then(|x| interpreter::check_limits(lua).map(|_| x));
+        // expressions may call resource(), which isn't relevant to the editor
+        interpreter::take_resource_directives(lua);
+        interpreter::take_generated_resources(lua);
+        result
+    }
+
+    fn diagnostics(&self, uri: &Url, text: &str) -> Vec<Diagnostic> {
+        let Ok(path) = uri.to_file_path() else {
+            return vec![];
+        };
+        let (expressions, mut diagnostics) = self.expressions(&path, text);
+
+        if DocumentKind::from_path(&path) == DocumentKind::Lua {
+            diagnostics.extend(self.script_diagnostics(&path, text));
+        }
+
+        // check that included files exist
+        if DocumentKind::from_path(&path) == DocumentKind::RtConfig {
+            let parent = path.parent().unwrap_or(Path::new("."));
+            for (line_idx, line) in text.lines().enumerate() {
+                if let Some(relpath) = include_on_line(line) {
+                    if !relpath.to_path(parent).is_file() {
+                        let range = Range::new(
+                            Position::new(line_idx as u32, 0),
+                            Position::new(line_idx as u32, line.chars().count() as u32),
+                        );
+                        diagnostics.push(diagnostic(
+                            range,
+                            format!("included file does not exist: {relpath}"),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !expressions.is_empty() {
+            let (lua, _) = self.interpreter(&path);
+            let env = Self::environment(&lua);
+            for expr in expressions {
+                if let Err(err) = Self::evaluate(&lua, &env, &expr) {
+                    diagnostics.push(diagnostic(expr.range, err.to_string()));
+                }
+            }
+        }
+
+        for diagnostic in diagnostics.iter_mut() {
+            diagnostic.range = range_to_utf16(text, diagnostic.range);
+        }
+        diagnostics
+    }
+
+    /// Check the documents changed since the last call
+    fn publish_diagnostics(&mut self) -> Result {
+        for uri in std::mem::take(&mut self.dirty) {
+            let Some(text) = self.documents.get(&uri) else {
+                continue;
+            };
+            let params =
+                PublishDiagnosticsParams::new(uri.clone(), self.diagnostics(&uri, text), None);
+            self.connection
+                .sender
+                .send(Notification::new(PublishDiagnostics::METHOD.into(), params).into())?;
+        }
+        Ok(())
+    }
+
+    fn hover(&self, uri: &Url, position: Position) -> Option<Hover> {
+        let path = uri.to_file_path().ok()?;
+        let text = self.documents.get(uri)?;
+        let (expressions, _) = self.expressions(&path, text);
+        let position = from_utf16(text, position);
+        let expr = expressions.iter().find(|x| contains(&x.range, &position))?;
+
+        let (lua, _) = self.interpreter(&path);
+        let contents = match Self::evaluate(&lua, &Self::environment(&lua), expr) {
+            Ok(value) => describe_value(&value).0,
+            Err(err) => format!("```\n{err}\n```"),
+        };
+
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: contents,
+            }),
+            range: Some(range_to_utf16(text, expr.range)),
+        })
+    }
+
+    fn document_colors(&self, uri: &Url) -> Vec<ColorInformation> {
+        let (Ok(path), Some(text)) = (uri.to_file_path(), self.documents.get(uri)) else {
+            return vec![];
+        };
+        let (expressions, _) = self.expressions(&path, text);
+        if expressions.is_empty() {
+            return vec![];
+        }
+
+        let (lua, _) = self.interpreter(&path);
+        let env = Self::environment(&lua);
+        expressions
+            .iter()
+            .filter_map(|expr| {
+                let value = Self::evaluate(&lua, &env, expr).ok()?;
+                let color = describe_value(&value).1?;
+                Some(ColorInformation {
+                    range: range_to_utf16(text, expr.range),
+                    color,
+                })
+            })
+            .collect()
+    }
+
+    fn color_presentations(color: Color, range: Range) -> Vec<ColorPresentation> {
+        let channel = |x: f32| (x * 255.0).round() as u8;
+        let (r, g, b, a) = (
+            channel(color.red),
+            channel(color.green),
+            channel(color.blue),
+            channel(color.alpha),
+        );
+        let label = if a == 255 {
+            format!("rgb({r}, {g}, {b})")
+        } else {
+            format!("rgba({r}, {g}, {b}, {a})")
+        };
+        vec![ColorPresentation {
+            text_edit: Some(TextEdit::new(range, label.clone())),
+            label,
+            additional_text_edits: None,
+        }]
+    }
+
+    fn definition(&self, uri: &Url, position: Position) -> Option<GotoDefinitionResponse> {
+        let path = uri.to_file_path().ok()?;
+        let text = self.documents.get(uri)?;
+        let position = from_utf16(text, position);
+        let line = text.lines().nth(position.line as usize)?;
+        let parent = path.parent().unwrap_or(Path::new("."));
+
+        if let Some(relpath) = include_on_line(line) {
+            let target = relpath.to_path(parent);
+            let uri = Url::from_file_path(target).ok()?;
+            return Some(GotoDefinitionResponse::Scalar(Location::new(
+                uri,
+                Range::default(),
+            )));
+        }
+
+        let name = global_at(line, position.character as usize)?;
+        for script_path in self.scripts(&path) {
+            let Some(script) = self.read(&script_path) else {
+                continue;
+            };
+            if let Some(range) = find_lua_definition(&script, &name) {
+                let uri = Url::from_file_path(script_path).ok()?;
+                let range = range_to_utf16(&script, range);
+                return Some(GotoDefinitionResponse::Scalar(Location::new(uri, range)));
+            }
+        }
+        None
+    }
+
+    fn completion(&self, uri: &Url, position: Position) -> Option<CompletionResponse> {
+        let text = self.documents.get(uri)?;
+        let position = from_utf16(text, position);
+        let line = text.lines().nth(position.line as usize).unwrap_or("");
+        let before: String = line.chars().take(position.character as usize).collect();
+        let word = before.trim_start().strip_prefix('#')?;
+        if !word.chars().all(|x| x.is_ascii_alphabetic()) {
+            return None;
+        }
+
+        let items = DIRECTIVES
+            .iter()
+            .filter(|(name, _, _)| name.starts_with(word))
+            .map(|(name, snippet, detail)| CompletionItem {
+                label: name.to_string(),
+                kind: Some(CompletionItemKind::KEYWORD),
+                detail: Some(detail.to_string()),
+                insert_text: Some(snippet.to_string()),
+                insert_text_format: Some(InsertTextFormat::SNIPPET),
+                ..Default::default()
+            })
+            .collect();
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn respond<R: serde::Serialize>(&self, id: RequestId, result: R) -> Result {
+        self.connection
+            .sender
+            .send(Response::new_ok(id, result).into())?;
+        Ok(())
+    }
+
+    fn handle_request(&self, req: Request) -> Result {
+        match req.method.as_str() {
+            HoverRequest::METHOD => {
+                let (id, params) = req.extract::<lsp_types::HoverParams>(HoverRequest::METHOD)?;
+                let pos = params.text_document_position_params;
+                self.respond(id, self.hover(&pos.text_document.uri, pos.position))
+            }
+            GotoDefinition::METHOD => {
+                let (id, params) =
+                    req.extract::<lsp_types::GotoDefinitionParams>(GotoDefinition::METHOD)?;
+                let pos = params.text_document_position_params;
+                self.respond(id, self.definition(&pos.text_document.uri, pos.position))
+            }
+            Completion::METHOD => {
+                let (id, params) =
+                    req.extract::<lsp_types::CompletionParams>(Completion::METHOD)?;
+                let pos = params.text_document_position;
+                self.respond(id, self.completion(&pos.text_document.uri, pos.position))
+            }
+            DocumentColor::METHOD => {
+                let (id, params) =
+                    req.extract::<lsp_types::DocumentColorParams>(DocumentColor::METHOD)?;
+                self.respond(id, self.document_colors(&params.text_document.uri))
+            }
+            ColorPresentationRequest::METHOD => {
+                let (id, params) = req.extract::<lsp_types::ColorPresentationParams>(
+                    ColorPresentationRequest::METHOD,
+                )?;
+                self.respond(id, Self::color_presentations(params.color, params.range))
+            }
+            _ => {
+                let response = Response::new_err(
+                    req.id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("unhandled method: {}", req.method),
+                );
+                self.connection.sender.send(response.into())?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Mark a changed document to be checked again. A change to a Lua script may affect every
+    /// document that includes it, so all open documents are checked then.
+    fn changed(&mut self, uri: Url) {
+        let is_lua = uri
+            .to_file_path()
+            .is_ok_and(|x| DocumentKind::from_path(&x) == DocumentKind::Lua);
+        if is_lua {
+            self.dirty.extend(self.documents.keys().cloned());
+        } else {
+            self.dirty.insert(uri);
+        }
+    }
+
+    fn handle_notification(&mut self, not: Notification) -> Result {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = not
+                    .extract::<lsp_types::DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                self.changed(uri);
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = not.extract::<lsp_types::DidChangeTextDocumentParams>(
+                    DidChangeTextDocument::METHOD,
+                )?;
+                // full sync, so the last change contains the whole document
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    let uri = params.text_document.uri;
+                    self.documents.insert(uri.clone(), change.text);
+                    self.changed(uri);
+                }
+            }
+            DidSaveTextDocument::METHOD => {
+                // included files and data loaded by scripts may have been written, so run the
+                // scripts again and check everything
+                *self.interpreter.get_mut() = None;
+                self.dirty.extend(self.documents.keys().cloned());
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = not.extract::<lsp_types::DidCloseTextDocumentParams>(
+                    DidCloseTextDocument::METHOD,
+                )?;
+                self.documents.remove(&params.text_document.uri);
+                self.dirty.remove(&params.text_document.uri);
+                // clear diagnostics of the closed document
+                let params = PublishDiagnosticsParams::new(params.text_document.uri, vec![], None);
+                self.connection
+                    .sender
+                    .send(Notification::new(PublishDiagnostics::METHOD.into(), params).into())?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn run(mut self) -> Result {
+        loop {
+            // wait for changes to settle before checking the changed documents
+            let msg = if self.dirty.is_empty() {
+                self.connection.receiver.recv().ok()
+            } else {
+                match self.connection.receiver.recv_timeout(DEBOUNCE) {
+                    Ok(msg) => Some(msg),
+                    Err(err) if err.is_timeout() => {
+                        self.publish_diagnostics()?;
+                        continue;
+                    }
+                    Err(_) => None,
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+
+            match msg {
+                Message::Request(req) => {
+                    if self.connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    self.handle_request(req)?;
+                }
+                Message::Notification(not) => self.handle_notification(not)?,
+                Message::Response(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["#".into()]),
+            ..Default::default()
+        }),
+        color_provider: Some(true.into()),
+        ..Default::default()
+    }
+}
+
+/// Run the language server over stdin/stdout until the client shuts it down
+pub fn run() -> Result {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = serde_json::to_value(capabilities())?;
+    let params = connection.initialize(capabilities)?;
+    let params: InitializeParams = serde_json::from_value(params)?;
+
+    Server::new(connection, params).run()?;
+    io_threads.join()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_expressions() {
+        let (expressions, diagnostics) =
+            rtconfig_expressions("set foo [#{a:arr()}]\nset bar #{\n  1 + 1\n}\n");
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            expressions,
+            vec![
+                Expression {
+                    code: "a:arr()".into(),
+                    range: Range::new(Position::new(0, 11), Position::new(0, 18)),
+                },
+                Expression {
+                    code: "\n  1 + 1\n".into(),
+                    range: Range::new(Position::new(1, 10), Position::new(3, 0)),
+                },
+            ]
+        );
+
+        let (expressions, diagnostics) = reapertheme_expressions(indoc! {"
+            [color theme]
+            col_tr1_bg=#{rgb(11, 22, 33)}
+            col_tr2_bg=255
+        "});
+        assert!(diagnostics.is_empty());
+        assert_eq!(
+            expressions,
+            vec![Expression {
+                code: "rgb(11, 22, 33)".into(),
+                range: Range::new(Position::new(1, 13), Position::new(1, 28)),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_utf16() {
+        let text = "a\nset x #{\"😀\" .. y}";
+        let position = Position::new(1, 15);
+        assert_eq!(to_utf16(text, position), Position::new(1, 16));
+        assert_eq!(from_utf16(text, Position::new(1, 16)), position);
+        // positions inside a surrogate pair map to the character
+        assert_eq!(from_utf16(text, Position::new(1, 12)), Position::new(1, 11));
+        assert_eq!(to_utf16(text, Position::new(0, 1)), Position::new(0, 1));
+        assert_eq!(to_utf16(text, Position::new(5, 3)), Position::new(5, 0));
+    }
+
+    #[test]
+    fn test_include_on_line() {
+        assert_eq!(
+            include_on_line(r#"  #include "./stuff.ini""#),
+            Some(RelativePathBuf::from("stuff.ini"))
+        );
+        assert_eq!(include_on_line(r#"#resource "*.png""#), None);
+    }
+
+    #[test]
+    fn test_global_at() {
+        let line = "set foo [#{my_colors.blue:arr()}]";
+        assert_eq!(global_at(line, 13), Some("my_colors".into()));
+        assert_eq!(global_at(line, 22), Some("my_colors".into()));
+        assert_eq!(global_at("x = 12", 5), None);
+    }
+
+    #[test]
+    fn test_find_lua_definition() {
+        let script = indoc! {"
+            my_colors = {
+                red = rgb(255, 0, 0),
+            }
+
+            local function helper() end
+
+            function darken(c)
+                return c
+            end
+        "};
+        assert_eq!(
+            find_lua_definition(script, "my_colors"),
+            Some(Range::new(Position::new(0, 0), Position::new(0, 9)))
+        );
+        assert_eq!(
+            find_lua_definition(script, "darken"),
+            Some(Range::new(Position::new(6, 9), Position::new(6, 15)))
+        );
+        assert_eq!(find_lua_definition(script, "red"), None);
+    }
+
+    #[test]
+    fn test_completion_directives() {
+        let (connection, _client) = Connection::memory();
+        let mut server = Server::new(connection, InitializeParams::default());
+        let uri = Url::parse("file:///theme/index.rtconfig.txt").unwrap();
+        server.documents.insert(uri.clone(), "#inc".into());
+
+        let Some(CompletionResponse::Array(items)) = server.completion(&uri, Position::new(0, 4))
+        else {
+            panic!("expected completions")
+        };
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].label, "include");
+    }
+
+    #[test]
+    fn test_interpreter_cache() {
+        let (connection, _client) = Connection::memory();
+        let mut server = Server::new(connection, InitializeParams::default());
+        let index = Url::parse("file:///theme/index.rtconfig.txt").unwrap();
+        let script = Url::parse("file:///theme/colors.lua").unwrap();
+        server
+            .documents
+            .insert(index.clone(), "#include \"colors.lua\"\nset a #{x}".into());
+        server.documents.insert(script.clone(), "x = 1".into());
+        let path = index.to_file_path().unwrap();
+
+        // scripts only run again when they change
+        let (lua, _) = server.interpreter(&path);
+        lua.globals().set("marker", true).unwrap();
+        let (lua, _) = server.interpreter(&path);
+        assert!(lua.globals().get::<bool>("marker").unwrap());
+        assert_eq!(lua.globals().get::<i64>("x").unwrap(), 1);
+
+        server.documents.insert(script, "x = 2".into());
+        let (lua, _) = server.interpreter(&path);
+        assert_eq!(lua.globals().get::<Option<bool>>("marker").unwrap(), None);
+        assert_eq!(lua.globals().get::<i64>("x").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_side_effects() {
+        let (connection, _client) = Connection::memory();
+        let mut server = Server::new(connection, InitializeParams::default());
+        let index = Url::parse("file:///theme/index.rtconfig.txt").unwrap();
+        let text = "set a #{assert(not seen, 'evaluated twice'); seen = true}";
+        server.documents.insert(index.clone(), text.into());
+
+        // each pass starts from the globals set by the scripts
+        for _ in 0..3 {
+            assert!(server.diagnostics(&index, text).is_empty());
+            let hover = server.hover(&index, Position::new(0, 8)).unwrap();
+            let HoverContents::Markup(contents) = hover.contents else {
+                panic!("expected markup")
+            };
+            assert!(!contents.value.contains("evaluated twice"));
+        }
+    }
+
+    #[test]
+    fn test_script_diagnostics() {
+        let (connection, _client) = Connection::memory();
+        let mut server = Server::new(connection, InitializeParams::default());
+        let script = Url::parse("file:///theme/colors.lua").unwrap();
+
+        let text = "x = 1\ny = = 2";
+        server.documents.insert(script.clone(), text.into());
+        let diagnostics = server.diagnostics(&script, text);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start.line, 1);
+
+        assert!(server.diagnostics(&script, "x = 1").is_empty());
+        assert_eq!(
+            error_line("runtime error: ...theme/colors.lua:3: boom", "colors.lua"),
+            Some(2)
+        );
+        assert_eq!(error_line("runtime error: boom", "colors.lua"), None);
+    }
+}
@@ -0,0 +1,217 @@
+use std::str::FromStr;
+
+/// Color space that colors are interpolated in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// Mix the sRGB channels directly
+    Rgb,
+    /// Mix hue, saturation and value, going the short way around the hue circle
+    Hsv,
+    /// Mix in the perceptually uniform Oklab space, which keeps the brightness even
+    Oklab,
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb" => Ok(Self::Rgb),
+            "hsv" => Ok(Self::Hsv),
+            "oklab" => Ok(Self::Oklab),
+            x => Err(format!(
+                "unknown color space `{}`, expected \"rgb\", \"hsv\" or \"oklab\"",
+                x
+            )),
+        }
+    }
+}
+
+fn to_channel(x: f64) -> u8 {
+    (x * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn srgb_to_linear(x: u8) -> f64 {
+    let x = x as f64 / 255.0;
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(x: f64) -> u8 {
+    let x = if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    };
+    to_channel(x)
+}
+
+/// Convert to Oklab lightness, green-red and blue-yellow, see https://bottosson.github.io/posts/oklab/
+pub fn to_oklab(rgb: [u8; 3]) -> [f64; 3] {
+    let [r, g, b] = rgb.map(srgb_to_linear);
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+/// Convert from Oklab, clamping colors outside the sRGB gamut
+pub fn from_oklab([lightness, a, b]: [f64; 3]) -> [u8; 3] {
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+    .map(linear_to_srgb)
+}
+
+/// Convert to hue in degrees, saturation and value
+pub fn to_hsv(rgb: [u8; 3]) -> [f64; 3] {
+    let [r, g, b] = rgb.map(|x| x as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    [hue, saturation, max]
+}
+
+pub fn from_hsv([hue, saturation, value]: [f64; 3]) -> [u8; 3] {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let [r, g, b] = match hue as u32 {
+        0 => [chroma, x, 0.0],
+        1 => [x, chroma, 0.0],
+        2 => [0.0, chroma, x],
+        3 => [0.0, x, chroma],
+        4 => [x, 0.0, chroma],
+        _ => [chroma, 0.0, x],
+    };
+    let m = value - chroma;
+    [r + m, g + m, b + m].map(to_channel)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+/// Mix two colors, `t` going from 0 at `from` to 1 at `to`
+pub fn interpolate(from: [u8; 3], to: [u8; 3], t: f64, space: ColorSpace) -> [u8; 3] {
+    match space {
+        ColorSpace::Rgb => {
+            [0, 1, 2].map(|i| to_channel(lerp(from[i] as f64, to[i] as f64, t) / 255.0))
+        }
+        ColorSpace::Hsv => {
+            let [mut h1, s1, v1] = to_hsv(from);
+            let [mut h2, s2, v2] = to_hsv(to);
+            // grays have no hue, so keep the hue of the other color
+            if s1 == 0.0 {
+                h1 = h2;
+            }
+            if s2 == 0.0 {
+                h2 = h1;
+            }
+            // go the short way around the hue circle
+            let delta = (h2 - h1 + 540.0).rem_euclid(360.0) - 180.0;
+            from_hsv([h1 + delta * t, lerp(s1, s2, t), lerp(v1, v2, t)])
+        }
+        ColorSpace::Oklab => {
+            let a = to_oklab(from);
+            let b = to_oklab(to);
+            from_oklab([0, 1, 2].map(|i| lerp(a[i], b[i], t)))
+        }
+    }
+}
+
+/// Positions of `count` evenly spaced colors from 0 to 1, including both ends
+pub fn steps(count: usize) -> impl Iterator<Item = f64> {
+    (0..count).map(move |i| match count {
+        1 => 0.0,
+        _ => i as f64 / (count - 1) as f64,
+    })
+}
+
+/// Rotate the hue of a color by some degrees and change its lightness from 0 to 1, keeping its
+/// chroma. Works in Oklab, so colors with the same lightness look equally bright.
+pub fn adjust(base: [u8; 3], hue: f64, lightness: f64) -> [u8; 3] {
+    let [l, a, b] = to_oklab(base);
+    let (sin, cos) = hue.to_radians().sin_cos();
+    from_oklab([
+        (l + lightness).clamp(0.0, 1.0),
+        a * cos - b * sin,
+        a * sin + b * cos,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        for color in [
+            [0, 0, 0],
+            [255, 255, 255],
+            [255, 0, 0],
+            [12, 200, 99],
+            [128, 128, 128],
+        ] {
+            assert_eq!(from_oklab(to_oklab(color)), color);
+            assert_eq!(from_hsv(to_hsv(color)), color);
+        }
+        assert_eq!(to_hsv([0, 0, 255]), [240.0, 1.0, 1.0]);
+        let [l, _, _] = to_oklab([255, 255, 255]);
+        assert!((l - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let red = [255, 0, 0];
+        let blue = [0, 0, 255];
+        assert_eq!(interpolate(red, blue, 0.5, ColorSpace::Rgb), [128, 0, 128]);
+        assert_eq!(interpolate(red, blue, 0.0, ColorSpace::Oklab), red);
+        assert_eq!(interpolate(red, blue, 1.0, ColorSpace::Oklab), blue);
+        // red to blue is shorter through magenta than through green
+        assert_eq!(interpolate(red, blue, 0.5, ColorSpace::Hsv), [255, 0, 255]);
+        // grays keep the hue of the other color
+        assert_eq!(
+            interpolate([0, 0, 0], [255, 0, 0], 0.5, ColorSpace::Hsv),
+            [128, 64, 64]
+        );
+
+        assert_eq!(steps(3).collect::<Vec<_>>(), vec![0.0, 0.5, 1.0]);
+        assert_eq!(steps(1).collect::<Vec<_>>(), vec![0.0]);
+        assert!("lab".parse::<ColorSpace>().is_err());
+    }
+
+    #[test]
+    fn test_adjust() {
+        let base = [200, 60, 60];
+        assert_eq!(adjust(base, 0.0, 0.0), base);
+        assert_eq!(adjust(base, 360.0, 0.0), base);
+        let lighter = to_oklab(adjust(base, 0.0, 0.1));
+        assert!((lighter[0] - to_oklab(base)[0] - 0.1).abs() < 0.01);
+        // rotating the hue keeps the lightness
+        let rotated = to_oklab(adjust(base, 120.0, 0.0));
+        assert!((rotated[0] - to_oklab(base)[0]).abs() < 0.02);
+        assert_eq!(adjust([255, 255, 255], 0.0, 0.5), [255, 255, 255]);
+    }
+}
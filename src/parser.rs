@@ -5,7 +5,7 @@ use nom::{
     bytes::complete::{escaped, tag, take, take_till, take_till1},
     character::complete::{alpha1, char, newline, space0, space1},
     combinator::{all_consuming, opt, recognize},
-    multi::{many0, many1},
+    multi::{many0, many1, separated_list1},
     sequence::{delimited, pair, preceded, terminated, tuple},
     Err, Finish, IResult, Parser, Slice,
 };
@@ -16,7 +16,7 @@ use thiserror::Error;
 
 pub(crate) type Input<'a> = LocatedSpan<&'a str>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ErrorLocation {
     pub offset: usize,
     pub line: u32,
@@ -86,6 +86,8 @@ pub enum ParseError {
     MalformedIncludeDirective(ErrorLocation),
     #[error("incorrect #resource syntax: {}", .0.fragment)]
     MalformedResourceDirective(ErrorLocation),
+    #[error("rename template uses a placeholder not in the pattern: {}", .0.fragment)]
+    UnknownRenamePlaceholder(ErrorLocation),
     #[error("invalid syntax: {}", .0.fragment)]
     Nom(ErrorLocation, nom::error::ErrorKind),
 }
@@ -101,6 +103,7 @@ impl ParseError {
             ParseError::NonWALTERHash(loc) => loc,
             ParseError::MalformedIncludeDirective(loc) => loc,
             ParseError::MalformedResourceDirective(loc) => loc,
+            ParseError::UnknownRenamePlaceholder(loc) => loc,
             ParseError::Nom(loc, _) => loc,
         }
     }
@@ -160,16 +163,68 @@ where
     serializer.serialize_str(pattern.as_str())
 }
 
+fn serialise_patterns<S>(
+    patterns: &[glob::Pattern],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(patterns.iter().map(|x| x.as_str()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResourceDirective {
+    /// May contain `{name}` placeholders, which match like `*` and can be used in `rename`.
+    /// Without `rename`, braces match literally.
+    #[serde(serialize_with = "serialise_pattern")]
+    pub pattern: glob::Pattern,
+    #[serde(serialize_with = "serialise_relpathbuf")]
+    pub dest: RelativePathBuf,
+    /// Files matching any of these patterns are skipped
+    #[serde(serialize_with = "serialise_patterns")]
+    pub exclude: Vec<glob::Pattern>,
+    /// Template for the destination file name, e.g. `knob_{n}.png`
+    pub rename: Option<String>,
+    /// Keep the path of each file relative to the pattern's base directory, instead of only
+    /// using its file name
+    pub preserve: bool,
+    /// Where the directive (or the call to `resource()`) is in its source file
+    #[serde(skip)]
+    pub location: ErrorLocation,
+}
+
+impl ResourceDirective {
+    /// The pattern as a plain glob pattern. Placeholders are replaced with `*` when there is a
+    /// rename template, otherwise braces are kept to match literally.
+    pub fn glob(&self) -> String {
+        match self.rename {
+            Some(_) => placeholders_to_glob(self.pattern.as_str()),
+            None => self.pattern.as_str().to_string(),
+        }
+    }
+}
+
+/// The leading directories of a glob pattern that don't contain any wildcards, e.g. `images` for
+/// `images/**/*.png`
+pub(crate) fn glob_base(pattern: &str) -> RelativePathBuf {
+    let mut base = RelativePathBuf::new();
+    let mut components = pattern.split('/').peekable();
+    while let Some(component) = components.next() {
+        // the last component is the file name
+        if components.peek().is_none() || component.contains(['*', '?', '[']) {
+            break;
+        }
+        base.push(component);
+    }
+    base
+}
+
 #[derive(Debug, Serialize)]
 pub enum Directive<'a> {
     #[serde(serialize_with = "serialise_relpathbuf")]
     Include(RelativePathBuf),
-    Resource {
-        #[serde(serialize_with = "serialise_pattern")]
-        pattern: glob::Pattern,
-        #[serde(serialize_with = "serialise_relpathbuf")]
-        dest: RelativePathBuf,
-    },
+    Resource(ResourceDirective),
     Unknown {
         #[serde(serialize_with = "serialise_span")]
         name: Input<'a>,
@@ -212,9 +267,61 @@ fn include_directive(input: Input) -> Result<Directive> {
     Ok((rest, Directive::Include(path)))
 }
 
+/// Names of the `{name}` placeholders in a resource pattern or rename template
+pub(crate) fn placeholders(text: &str) -> Vec<&str> {
+    text.split('{')
+        .skip(1)
+        .filter_map(|x| x.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+/// Convert a resource pattern with `{name}` placeholders to a plain glob pattern
+pub(crate) fn placeholders_to_glob(pattern: &str) -> String {
+    let mut result = pattern.to_string();
+    for name in placeholders(pattern) {
+        result = result.replace(&format!("{{{name}}}"), "*");
+    }
+    result
+}
+
+/// Parse a resource pattern. With a rename template, it must still be a valid glob pattern once
+/// its placeholders are replaced with `*`.
+pub(crate) fn resource_pattern(
+    pattern: &str,
+    rename: bool,
+) -> std::result::Result<glob::Pattern, glob::PatternError> {
+    if rename {
+        glob::Pattern::new(&placeholders_to_glob(pattern))?;
+    }
+    glob::Pattern::new(pattern)
+}
+
+fn glob_pattern_string(input: Input) -> Result<glob::Pattern> {
+    let (rest, (pattern, raw_pattern)) = string(input)?;
+    let pattern = glob::Pattern::new(&pattern).or(Err(Err::Failure(
+        ParseError::InvalidGlobPattern(raw_pattern.into()),
+    )))?;
+    Ok((rest, pattern))
+}
+
+fn resource_exclude(input: Input) -> Result<Vec<glob::Pattern>> {
+    preceded(
+        tuple((space1, tag("exclude"), space1)),
+        separated_list1(tuple((space0, char(','), space0)), glob_pattern_string),
+    )(input)
+}
+
+fn resource_preserve(input: Input) -> Result<bool> {
+    preceded(space1, tag("preserve")).map(|_| true).parse(input)
+}
+
+fn resource_rename(input: Input) -> Result<(RelativePathBuf, Input)> {
+    preceded(tuple((space1, tag("as"), space1)), relative_path_string)(input)
+}
+
 fn resource_directive(input: Input) -> Result<Directive> {
     let (rest, tag) = tag("#resource")(input)?;
-    let (rest, (dest, (pattern, raw_pattern))) = preceded(
+    let (rest, (dest, (pattern, raw_pattern), exclude, preserve, rename)) = preceded(
         space1,
         tuple((
             opt(terminated(
@@ -222,6 +329,9 @@ fn resource_directive(input: Input) -> Result<Directive> {
                 tuple((space0, char(':'), space0)),
             )),
             relative_path_string,
+            many0(resource_exclude),
+            opt(resource_preserve),
+            opt(resource_rename),
         )),
     )(rest)
     .map_err(|err| {
@@ -239,11 +349,38 @@ fn resource_directive(input: Input) -> Result<Directive> {
         .unwrap_or(RelativePathBuf::from(".").normalize());
 
     // parse pattern
-    let pattern = glob::Pattern::new(pattern.as_str()).or(Err(Err::Failure(
+    let pattern = resource_pattern(pattern.as_str(), rename.is_some()).or(Err(Err::Failure(
         ParseError::InvalidGlobPattern(raw_pattern.into()),
     )))?;
 
-    Ok((rest, Directive::Resource { pattern, dest }))
+    // the rename template may only use placeholders from the pattern
+    let rename = match rename {
+        Some((template, raw_template)) => {
+            let known = placeholders(pattern.as_str());
+            if placeholders(template.as_str())
+                .iter()
+                .any(|x| !known.contains(x))
+            {
+                return Err(Err::Failure(ParseError::UnknownRenamePlaceholder(
+                    raw_template.into(),
+                )));
+            }
+            Some(template.to_string())
+        }
+        None => None,
+    };
+
+    Ok((
+        rest,
+        Directive::Resource(ResourceDirective {
+            pattern,
+            dest,
+            exclude: exclude.into_iter().flatten().collect(),
+            rename,
+            preserve: preserve.unwrap_or(false),
+            location: tag.into(),
+        }),
+    ))
 }
 
 fn unknown_directive(input: Input) -> Result<Directive> {
@@ -420,7 +557,7 @@ pub fn parse_reapertheme(input: &str) -> std::result::Result<Vec<ReaperThemeCont
 mod tests {
     use std::{borrow::Cow, fmt::Debug};
 
-    use nom::Finish;
+    use nom::{sequence::Tuple, Finish};
 
     use super::*;
 
@@ -536,6 +673,62 @@ mod tests {
         bad(walter_code("".into()));
     }
 
+    #[test]
+    fn test_resource_directive() {
+        ok(resource_directive(
+            r#"#resource "*.png" exclude "*_wip.png""#.into(),
+        ));
+        ok(resource_directive(
+            r#"#resource "150": "*.png" exclude "*_wip.png", "old/*""#.into(),
+        ));
+        ok(resource_directive(
+            r#"#resource "src/knob_{n}.png" as "knob{n}.png""#.into(),
+        ));
+        ok(resource_directive(
+            r#"#resource "src/knob_{n}.png" exclude "*_wip.png" as "knob{n}.png""#.into(),
+        ));
+        irrecoverable(resource_directive(
+            r#"#resource "src/knob_{n}.png" as "knob{m}.png""#.into(),
+        ));
+        // `knob_**.png` once the placeholder is replaced
+        irrecoverable(resource_directive(
+            r#"#resource "knob_*{n}.png" as "k{n}.png""#.into(),
+        ));
+
+        let (_, directive) = resource_directive(
+            r#"#resource "200": "src/knob_{n}.png" exclude "*_wip.png", "old_*" as "knob{n}.png""#
+                .into(),
+        )
+        .unwrap();
+        let Directive::Resource(resource) = directive else {
+            panic!("expected resource directive")
+        };
+        assert_eq!(resource.dest.as_str(), "200");
+        assert_eq!(resource.pattern.as_str(), "src/knob_{n}.png");
+        assert_eq!(resource.exclude.len(), 2);
+        assert_eq!(resource.rename.as_deref(), Some("knob{n}.png"));
+        assert!(!resource.preserve);
+        assert_eq!(placeholders_to_glob("src/{a}_{b}.png"), "src/*_*.png");
+
+        let (_, directive) = resource_directive(
+            r#"#resource "images/**/*.png" exclude "*_wip.png" preserve"#.into(),
+        )
+        .unwrap();
+        let Directive::Resource(resource) = directive else {
+            panic!("expected resource directive")
+        };
+        assert!(resource.preserve);
+
+        assert_eq!(glob_base("images/**/*.png").as_str(), "images");
+        assert_eq!(glob_base("images/150/*.png").as_str(), "images/150");
+        assert_eq!(glob_base("*.png").as_str(), "");
+        assert_eq!(glob_base("src/{kind}/knob.png").as_str(), "src/{kind}");
+        assert_eq!(
+            glob_base(&placeholders_to_glob("src/{kind}/knob.png")).as_str(),
+            "src"
+        );
+    }
+
     #[test]
     fn test_rtconfig() {
         let text = std::fs::read_to_string("test/test.rtconfig.txt").unwrap();
@@ -558,8 +751,8 @@ mod tests {
                         }
                         RtconfigContent::Directive(dir) => match dir {
                             Directive::Include(path) => format!("#include \"{path}\"").into(),
-                            Directive::Resource { pattern, dest } => {
-                                format!("#resource \"{dest}\": \"{pattern}\"").into()
+                            Directive::Resource(res) => {
+                                format!("#resource \"{}\": \"{}\"", res.dest, res.pattern).into()
                             }
                             Directive::Unknown { name, contents } => {
                                 format!("#UNKNOWN ; #{name}{contents}").into()
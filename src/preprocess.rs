@@ -1,24 +1,28 @@
 use std::{
     borrow::Cow,
     collections::HashMap,
+    ffi::c_void,
     fs,
     path::{Path, PathBuf},
 };
 
-use glob::Pattern;
 use ini::Ini;
-use log::{debug, warn};
-use relative_path::RelativePath;
-
+use log::debug;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
-    interpreter::{self, RGB, RGBA},
+    cache,
+    diagnostics::{self, Diagnostic},
+    images::Image,
+    interpreter::{self, Blend, InterpreterOptions, Toggle, RGB, RGBA},
     parser::{
-        self, parse_reapertheme, Directive, ErrorLocation, ParseError, ReaperThemeContent,
-        RtconfigContent,
+        self, glob_base, parse_reapertheme, Directive, ErrorLocation, ParseError,
+        ReaperThemeContent, ResourceDirective, RtconfigContent,
     },
-    theme::ResourceMap,
+    theme::{Resource, ResourceMap},
+    theme_keys::{self, ValueType},
 };
 
 #[derive(Error, Debug)]
@@ -38,7 +42,11 @@ pub enum PreprocessError {
     #[error("{0}: failed to read script file ({1})")]
     ReadScriptError(PathBuf, std::io::Error),
     #[error("{0}:{1}: failed to evaluate lua code: {2}")]
-    EvaluateError(PathBuf, ErrorLocation, mlua::Error),
+    EvaluateError(PathBuf, ErrorLocation, Box<mlua::Error>),
+    #[error("{0}:{1}: resource pattern `{2}` did not match any files")]
+    EmptyResourceGlob(PathBuf, ErrorLocation, String),
+    #[error("{0}:{1}: invalid resource pattern `{2}`")]
+    InvalidResourceGlob(PathBuf, ErrorLocation, String),
 }
 
 impl PreprocessError {
@@ -52,6 +60,8 @@ impl PreprocessError {
             PreprocessError::IniError(path, _) => path.as_path(),
             PreprocessError::ReadScriptError(path, _) => path.as_path(),
             PreprocessError::EvaluateError(path, _, _) => path.as_path(),
+            PreprocessError::EmptyResourceGlob(path, _, _) => path.as_path(),
+            PreprocessError::InvalidResourceGlob(path, _, _) => path.as_path(),
         }
     }
 
@@ -65,6 +75,56 @@ impl PreprocessError {
             Self::IniError(..) => "failed to read reapertheme file",
             Self::ReadScriptError(..) => "failed to read script file",
             Self::EvaluateError(..) => "failed to evaluate lua code",
+            Self::EmptyResourceGlob(..) => "resource pattern did not match any files",
+            Self::InvalidResourceGlob(..) => "invalid resource pattern",
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Self::IncludeOutsideRoot(..) => "include-outside-root",
+            Self::ResourceOutsideRoot(..) => "resource-outside-root",
+            Self::ReadError(..) => "read-error",
+            Self::RtconfigParseError(..) => "rtconfig-parse-error",
+            Self::ReaperThemeParseError(..) => "reapertheme-parse-error",
+            Self::IniError(..) => "ini-error",
+            Self::ReadScriptError(..) => "read-script-error",
+            Self::EvaluateError(..) => "evaluate-error",
+            Self::EmptyResourceGlob(..) => "resource-empty-glob",
+            Self::InvalidResourceGlob(..) => "resource-invalid-glob",
+        }
+    }
+}
+
+impl From<&PreprocessError> for Diagnostic {
+    fn from(err: &PreprocessError) -> Self {
+        let message = match err {
+            PreprocessError::RtconfigParseError(_, inner)
+            | PreprocessError::ReaperThemeParseError(_, inner) => {
+                format!("{}: {}", err.message(), inner)
+            }
+            PreprocessError::IniError(_, inner) => format!("{}: {}", err.message(), inner),
+            PreprocessError::ReadScriptError(_, inner) => format!("{}: {}", err.message(), inner),
+            PreprocessError::EvaluateError(_, _, inner) => format!("{}: {}", err.message(), inner),
+            PreprocessError::EmptyResourceGlob(_, _, pattern) => {
+                format!("resource pattern `{}` did not match any files", pattern)
+            }
+            PreprocessError::InvalidResourceGlob(_, _, pattern) => {
+                format!("invalid resource pattern `{}`", pattern)
+            }
+            _ => err.message().to_string(),
+        };
+        let diagnostic = Diagnostic::error(err.code(), message).file(err.path());
+
+        match err {
+            PreprocessError::RtconfigParseError(_, inner)
+            | PreprocessError::ReaperThemeParseError(_, inner) => {
+                diagnostic.location(inner.location())
+            }
+            PreprocessError::EvaluateError(_, location, _)
+            | PreprocessError::EmptyResourceGlob(_, location, _)
+            | PreprocessError::InvalidResourceGlob(_, location, _) => diagnostic.location(location),
+            _ => diagnostic,
         }
     }
 }
@@ -83,28 +143,219 @@ fn parse_rtconfig<'text, 'path>(
         .map_err(|err| PreprocessError::RtconfigParseError(path.to_path_buf(), err))
 }
 
+/// Serialise colors, toggles and blend values as the numbers used in *.ReaperTheme
+fn serialise_userdata(userdata: &mlua::AnyUserData) -> mlua::Result<String> {
+    if let Ok(color) = userdata.borrow::<RGB>() {
+        Ok(color.value_rev().to_string())
+    } else if let Ok(color) = userdata.borrow::<RGBA>() {
+        Ok(color.value_rev().to_string())
+    } else if let Ok(blend) = userdata.borrow::<Blend>() {
+        Ok(blend.value().to_string())
+    } else if let Ok(toggle) = userdata.borrow::<Toggle>() {
+        Ok(toggle.value().to_string())
+    } else if userdata.is::<Image>() {
+        Err(mlua::Error::runtime(
+            "images can't be serialised; add them to the theme with resource(dest, image)",
+        ))
+    } else {
+        Err(mlua::Error::runtime("cannot serialise userdata"))
+    }
+}
+
+/// Whether a value is a color or a blend value
+fn typed_value(value: &mlua::Value) -> Option<ValueType> {
+    let mlua::Value::UserData(userdata) = value else {
+        return None;
+    };
+    if userdata.is::<RGB>() || userdata.is::<RGBA>() || userdata.is::<Toggle>() {
+        Some(ValueType::Color)
+    } else if userdata.is::<Blend>() {
+        Some(ValueType::Blend)
+    } else {
+        None
+    }
+}
+
+/// Warn when a blend value is given to a color key, or a color to a blend mode key, in the
+/// `[color theme]` section
+fn check_value_type(
+    path: &Path,
+    expr: &parser::Input,
+    section: Option<&str>,
+    key: &str,
+    value: &mlua::Value,
+) {
+    if section != Some("color theme") {
+        return;
+    }
+    let (Some(expected), Some(given)) = (theme_keys::value_type(key), typed_value(value)) else {
+        return;
+    };
+    let message = match (expected, given) {
+        (ValueType::Color, ValueType::Blend) => {
+            format!("`{}` expects a color, but was given a blend value", key)
+        }
+        (ValueType::Blend, ValueType::Color) => {
+            format!("`{}` expects a blend value, but was given a color", key)
+        }
+        _ => return,
+    };
+    Diagnostic::warning("value-type-mismatch", message)
+        .file(path)
+        .location(&expr.into())
+        .emit();
+}
+
+/// Serialise an array as its items separated by spaces, e.g. a list of colors from `gradient()`
+fn serialise_table(table: &mlua::Table) -> mlua::Result<String> {
+    serialise_nested_table(table, &mut Vec::new())
+}
+
+/// `parents` holds the tables being serialised around this one, to catch tables that contain
+/// themselves
+fn serialise_nested_table(
+    table: &mlua::Table,
+    parents: &mut Vec<*const c_void>,
+) -> mlua::Result<String> {
+    if parents.contains(&table.to_pointer()) {
+        return Err(mlua::Error::runtime(
+            "cannot serialise a table that contains itself",
+        ));
+    }
+    parents.push(table.to_pointer());
+
+    let mut items = Vec::new();
+    for value in table.clone().sequence_values::<mlua::Value>() {
+        let item = match value? {
+            mlua::Value::Boolean(x) => x.to_string(),
+            mlua::Value::Integer(x) => x.to_string(),
+            mlua::Value::Number(x) => x.to_string(),
+            mlua::Value::String(x) => x.to_str()?.to_string(),
+            mlua::Value::Table(x) => serialise_nested_table(&x, parents)?,
+            mlua::Value::UserData(userdata) => serialise_userdata(&userdata)?,
+            x => {
+                return Err(mlua::Error::runtime(format!(
+                    "cannot serialise {} in a table",
+                    x.type_name()
+                )))
+            }
+        };
+        items.push(item);
+    }
+
+    let mut count = 0;
+    table.for_each(|_: mlua::Value, _: mlua::Value| {
+        count += 1;
+        Ok(())
+    })?;
+    if count != items.len() {
+        return Err(mlua::Error::runtime(
+            "only arrays can be serialised, the table has keys that aren't 1, 2, 3...",
+        ));
+    }
+
+    parents.pop();
+    Ok(items.join(" "))
+}
+
 enum IncludeType {
     RtConfig,
     ReaperTheme,
     Lua,
 }
 
+/// What to do when a resource pattern doesn't match any files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyGlobPolicy {
+    Warn,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct PreprocessOptions {
+    empty_glob: EmptyGlobPolicy,
+    interpreter: InterpreterOptions,
+}
+
+impl Default for PreprocessOptions {
+    fn default() -> Self {
+        Self {
+            empty_glob: EmptyGlobPolicy::Warn,
+            interpreter: InterpreterOptions::default(),
+        }
+    }
+}
+
+impl PreprocessOptions {
+    pub fn empty_glob(mut self, x: EmptyGlobPolicy) -> Self {
+        self.empty_glob = x;
+        self
+    }
+
+    pub fn interpreter(mut self, x: InterpreterOptions) -> Self {
+        self.interpreter = x;
+        self
+    }
+}
+
+/// A line in a source file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceLocation {
+    pub file: PathBuf,
+    pub line: u32,
+}
+
+impl SourceLocation {
+    fn new(file: &Path, line: u32) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            line,
+        }
+    }
+}
+
+/// The source location of each line in the output rtconfig; the first item is line 1
+pub type SourceMap = Vec<SourceLocation>;
+
+pub struct PreprocessOutput {
+    pub rtconfig: String,
+    pub reapertheme: Ini,
+    pub resources: ResourceMap,
+    pub source_map: SourceMap,
+    /// files, resource patterns and environment variables that the output depends on
+    pub inputs: cache::Inputs,
+    /// warnings emitted while preprocessing, repeated when the output is loaded from the cache
+    pub diagnostics: Vec<Diagnostic>,
+}
+
 struct ThemeBuilder {
+    options: PreprocessOptions,
     lua: mlua::Lua,
     parts: Vec<String>,
     config: Ini,
     resources: ResourceMap,
     skip_next_newline: bool,
+    /// the location in the source file currently being fed
+    location: SourceLocation,
+    /// the source location of the output line currently being written
+    line_origin: Option<SourceLocation>,
+    source_map: SourceMap,
 }
 
 impl ThemeBuilder {
-    fn new() -> Self {
+    fn with_options(options: PreprocessOptions) -> Self {
+        let lua = interpreter::new_with_options(&options.interpreter);
+        lua.set_app_data(cache::Inputs::default());
         Self {
-            lua: interpreter::new(),
+            options,
+            lua,
             parts: Vec::new(),
             config: Ini::new(),
             resources: HashMap::new(),
             skip_next_newline: false,
+            location: SourceLocation::new("".as_ref(), 1),
+            line_origin: None,
+            source_map: Vec::new(),
         }
     }
 
@@ -112,6 +363,31 @@ impl ThemeBuilder {
         self.parts.join("")
     }
 
+    fn source_map(&self) -> SourceMap {
+        let mut source_map = self.source_map.clone();
+        if let Some(origin) = &self.line_origin {
+            source_map.push(origin.clone());
+        }
+        source_map
+    }
+
+    /// Add text to the output rtconfig, recording the current source location for each new line
+    fn push(&mut self, text: String) {
+        for (i, segment) in text.split('\n').enumerate() {
+            if i > 0 {
+                let origin = self
+                    .line_origin
+                    .take()
+                    .unwrap_or_else(|| self.location.clone());
+                self.source_map.push(origin);
+            }
+            if !segment.is_empty() && self.line_origin.is_none() {
+                self.line_origin = Some(self.location.clone());
+            }
+        }
+        self.parts.push(text);
+    }
+
     fn reapertheme(&self) -> &Ini {
         &self.config
     }
@@ -126,30 +402,31 @@ impl ThemeBuilder {
                 if self.skip_next_newline {
                     self.skip_next_newline = false;
                 } else {
-                    self.parts.push("\n".into());
+                    self.push("\n".into());
                 }
+                self.location.line += 1;
+            }
+            RtconfigContent::Code(text) => {
+                self.location.line = text.location_line();
+                self.push(text.fragment().to_string())
+            }
+            RtconfigContent::Comment(text) => {
+                self.location.line = text.location_line();
+                self.push(text.fragment().to_string())
+            }
+            RtconfigContent::Expression(text) => {
+                self.set_source_path(source_path);
+                self.feed_expression(text).map_err(|err| {
+                    PreprocessError::EvaluateError(source_path.into(), text.into(), Box::new(err))
+                })?;
+                self.feed_lua_resources(source_path, Some(text))?;
             }
-            RtconfigContent::Code(text) => self.parts.push(text.fragment().to_string()),
-            RtconfigContent::Comment(text) => self.parts.push(text.fragment().to_string()),
-            RtconfigContent::Expression(text) => self.feed_expression(text).and_then(|_| {
-                let mut directives = interpreter::NEW_RESOURCE_PATHS.lock().unwrap();
-                for x in directives.iter() {
-                    let Directive::Resource { pattern, dest } = x else {
-                        panic!("NEW_RESOURCE_PATHS should only contain Directive::Resource instances")
-                    };
-                    self.feed_directive_resource(&pattern, &dest, &source_path);
-                }
-                directives.clear();;
-                Ok(())
-            }).map_err(|err| {
-                PreprocessError::EvaluateError(source_path.into(), text.into(), err)
-            })?,
             RtconfigContent::Directive(dir) => {
                 self.skip_next_newline = true;
                 match dir {
                     Directive::Include(path) => self.feed_directive_include(&path, &source_path)?,
-                    Directive::Resource { pattern, dest } => {
-                        self.feed_directive_resource(&pattern, &dest, &source_path)
+                    Directive::Resource(resource) => {
+                        self.feed_directive_resource(resource, source_path)?
                     }
                     Directive::Unknown { name, contents } => {
                         self.feed_directive_unknown(name, contents)
@@ -160,9 +437,18 @@ impl ThemeBuilder {
         Ok(())
     }
 
+    /// Set the file that Lua code is run from, for resolving relative paths
+    fn set_source_path(&self, path: &Path) {
+        self.lua
+            .set_app_data(interpreter::SourcePath(path.to_path_buf()));
+    }
+
     fn import_config(&mut self, path: &Path) -> Result {
-        let ini = Ini::load_from_file(path)
-            .map_err(|err| PreprocessError::IniError(path.to_path_buf(), err))?;
+        self.set_source_path(path);
+        let text = read(path)?;
+        cache::record(&self.lua, |x| x.file(path, text.as_bytes()));
+        let ini = Ini::load_from_str(&text)
+            .map_err(|err| PreprocessError::IniError(path.to_path_buf(), ini::Error::Parse(err)))?;
 
         for (section, prop) in ini.iter() {
             for (key, value) in prop.iter() {
@@ -176,11 +462,19 @@ impl ThemeBuilder {
                     .iter()
                     .map(|x| match x {
                         ReaperThemeContent::Text(text) => Ok(Cow::from(*text.fragment())),
-                        ReaperThemeContent::Expression(text) => {
-                            self.serialise_expression(text, false).map_err(|err| {
-                                PreprocessError::EvaluateError(path.into(), text.into(), err)
+                        ReaperThemeContent::Expression(text) => self
+                            .evaluate_expression(text)
+                            .and_then(|value| {
+                                check_value_type(path, text, section, key, &value);
+                                self.serialise_value(text, value)
                             })
-                        }
+                            .map_err(|err| {
+                                PreprocessError::EvaluateError(
+                                    path.into(),
+                                    text.into(),
+                                    Box::new(err),
+                                )
+                            }),
                     })
                     .collect();
                 let value = value?;
@@ -193,14 +487,18 @@ impl ThemeBuilder {
     }
 
     fn run_script(&self, path: &Path) -> Result {
+        self.set_source_path(path);
         let script = std::fs::read_to_string(path)
             .map_err(|err| PreprocessError::ReadScriptError(path.to_path_buf(), err))?;
+        cache::record(&self.lua, |x| x.file(path, script.as_bytes()));
+        interpreter::reset_limits(&self.lua);
         self.lua
             .load(script)
             .set_name(path.to_string_lossy())
             .exec()
+            .and_then(|_| interpreter::check_limits(&self.lua))
             .map_err(|err| {
-                PreprocessError::EvaluateError(path.into(), ErrorLocation::default(), err)
+                PreprocessError::EvaluateError(path.into(), ErrorLocation::default(), Box::new(err))
             })?;
         Ok(())
     }
@@ -209,14 +507,26 @@ impl ThemeBuilder {
         &self,
         expr: &parser::Input,
         is_rtconfig: bool,
-    ) -> mlua::Result<Cow<str>> {
-        let value: mlua::Value = self
-            .lua
+    ) -> mlua::Result<Cow<'_, str>> {
+        let value = self.evaluate_expression(expr)?;
+        self.serialise_value(expr, value)
+    }
+
+    fn evaluate_expression(&self, expr: &parser::Input) -> mlua::Result<mlua::Value> {
+        interpreter::reset_limits(&self.lua);
+        self.lua
             .load(*expr.fragment())
             .set_mode(mlua::ChunkMode::Text)
             .set_name(*expr.fragment())
-            .eval()?;
+            .eval()
+            .and_then(|x| interpreter::check_limits(&self.lua).map(|_| x))
+    }
 
+    fn serialise_value(
+        &self,
+        expr: &parser::Input,
+        value: mlua::Value,
+    ) -> mlua::Result<Cow<'_, str>> {
         match value {
             mlua::Value::Nil => Ok("".into()),
             mlua::Value::Boolean(true) => Ok("true".into()),
@@ -232,29 +542,24 @@ impl ThemeBuilder {
                 let indented_x = indent::indent_by(column, x);
                 Ok(indented_x.into())
             }
-            mlua::Value::Table(_) => todo!("Table"),
-            mlua::Value::Function(_) => todo!("Function"),
-            mlua::Value::Thread(_) => todo!("Thread"),
-            mlua::Value::UserData(userdata) => {
-                if let Ok(color) = userdata.borrow::<RGB>() {
-                    Ok(color.value_rev().to_string().into())
-                } else if let Ok(color) = userdata.borrow::<RGBA>() {
-                    Ok(color.value_rev().to_string().into())
-                } else {
-                    todo!("UserData")
-                }
-            }
-            mlua::Value::LightUserData(_) => todo!("LightUserData"),
-            mlua::Value::Other(..) => todo!("Other"),
-            mlua::Value::Error(_) => todo!("Error"),
+            mlua::Value::Table(table) => Ok(serialise_table(&table)?.into()),
+            mlua::Value::UserData(userdata) => Ok(serialise_userdata(&userdata)?.into()),
+            x => Err(mlua::Error::runtime(format!(
+                "cannot serialise {}",
+                x.type_name()
+            ))),
         }
     }
 
     fn feed_expression(&mut self, expr: &parser::Input) -> mlua::Result<()> {
-        let expr = self.serialise_expression(expr, true)?;
-        let expr = expr.to_string();
+        self.location.line = expr.location_line();
+        let text = self.serialise_expression(expr, true)?;
+        let text = text.to_string();
+
+        self.push(text);
 
-        self.parts.push(expr.into());
+        // expressions may span multiple lines
+        self.location.line += expr.fragment().matches('\n').count() as u32;
 
         Ok(())
     }
@@ -284,15 +589,54 @@ impl ThemeBuilder {
             IncludeType::Lua => self.run_script(&include_path)?,
         }
 
+        // the included file may have called resource()
+        self.feed_lua_resources(&include_path, None)
+    }
+
+    /// Add resources from calls to `resource()` in Lua code. If the code is an expression, the
+    /// location of the calls is set to the location of the expression.
+    fn feed_lua_resources(&mut self, source_path: &Path, expr: Option<&parser::Input>) -> Result {
+        for mut resource in interpreter::take_resource_directives(&self.lua) {
+            if let Some(expr) = expr {
+                resource.location = expr.into();
+            }
+            self.feed_directive_resource(&resource, source_path)?;
+        }
+
+        for (dest, resource) in interpreter::take_generated_resources(&self.lua) {
+            if self.resources.contains_key(&dest) {
+                Diagnostic::warning(
+                    "resource-overwrite",
+                    format!(
+                        "generated resource overwrites previous resource at `{}`",
+                        dest
+                    ),
+                )
+                .file(source_path)
+                .location(&expr.map(ErrorLocation::from).unwrap_or_default())
+                .emit();
+                continue;
+            }
+            self.resources.insert(dest, resource);
+        }
+
         Ok(())
     }
 
     fn feed_directive_resource(
         &mut self,
-        pattern: &Pattern,
-        dest: &RelativePath,
+        resource: &ResourceDirective,
         source_path: &Path,
-    ) {
+    ) -> Result {
+        let ResourceDirective {
+            pattern,
+            dest,
+            exclude,
+            rename,
+            preserve,
+            location,
+        } = resource;
+        let glob = resource.glob();
         let source_dir = source_path.parent().unwrap();
         debug!(
             "glob pattern `{}` starting from `{}`",
@@ -300,54 +644,220 @@ impl ThemeBuilder {
             source_dir.to_string_lossy()
         );
 
-        let absolute_pattern = source_dir.join(pattern.as_str());
-        let resources = glob::glob(absolute_pattern.to_string_lossy().as_ref()).expect(
-            format!(
-                "invalid glob pattern `{}`",
-                absolute_pattern.to_string_lossy()
+        let absolute_pattern = source_dir.join(&glob);
+        // the pattern was checked when parsing, but the folder may contain wildcards too
+        let resources = glob::glob(absolute_pattern.to_string_lossy().as_ref()).map_err(|_| {
+            PreprocessError::InvalidResourceGlob(
+                source_path.to_path_buf(),
+                location.clone(),
+                absolute_pattern.to_string_lossy().to_string(),
             )
-            .as_str(),
-        );
+        })?;
+        let resources: Vec<_> = resources.collect();
+        cache::record(&self.lua, |x| {
+            let paths: Vec<PathBuf> = resources
+                .iter()
+                .filter_map(|x| x.as_ref().ok().cloned())
+                .collect();
+            x.glob(absolute_pattern.to_string_lossy().as_ref(), &paths)
+        });
 
+        let mut matched = 0;
         for path in resources {
             match path {
-                Err(err) => warn!(
-                    "failed to get resources in path `{}`: {}",
-                    err.path().to_string_lossy(),
-                    err.error()
-                ),
-                Ok(path) => match path.file_name() {
-                    None => warn!(
-                        "resource does not have a filename `{}`",
-                        path.to_string_lossy()
+                Err(err) => Diagnostic::warning(
+                    "resource-glob-error",
+                    format!(
+                        "failed to get resources in path `{}`: {}",
+                        err.path().to_string_lossy(),
+                        err.error()
                     ),
+                )
+                .file(source_path)
+                .emit(),
+                Ok(path) => match path.file_name() {
+                    None => Diagnostic::warning(
+                        "resource-no-filename",
+                        format!(
+                            "resource does not have a filename `{}`",
+                            path.to_string_lossy()
+                        ),
+                    )
+                    .file(source_path)
+                    .emit(),
                     Some(file_name) => {
-                        let dest_file = dest.join(file_name.to_string_lossy().as_ref());
+                        // path relative to the including file, for matching exclusions and
+                        // placeholders
+                        let relpath = match RelativePathBuf::from_path(
+                            path.strip_prefix(source_dir).unwrap_or(&path),
+                        ) {
+                            Ok(x) => x,
+                            Err(_) => RelativePathBuf::from(file_name.to_string_lossy().as_ref()),
+                        };
+
+                        if exclude.iter().any(|x| {
+                            x.matches(relpath.as_str())
+                                || x.matches(file_name.to_string_lossy().as_ref())
+                        }) {
+                            debug!("excluded resource `{}`", relpath);
+                            continue;
+                        }
+                        matched += 1;
+
+                        let dest_name = match rename {
+                            Some(template) => {
+                                match rename_resource(pattern.as_str(), relpath.as_str(), template)
+                                {
+                                    Some(x) => x,
+                                    None => {
+                                        Diagnostic::warning(
+                                            "resource-rename",
+                                            format!(
+                                                "failed to match `{}` against pattern `{}` for renaming",
+                                                relpath, pattern
+                                            ),
+                                        )
+                                        .file(source_path)
+                                        .emit();
+                                        continue;
+                                    }
+                                }
+                            }
+                            None => file_name.to_string_lossy().to_string(),
+                        };
+                        let dest_file = if *preserve {
+                            // keep the directories between the pattern's base and the file
+                            let base = glob_base(&glob);
+                            let dir = relpath
+                                .parent()
+                                .and_then(|x| x.strip_prefix(&base).ok())
+                                .map(|x| x.to_relative_path_buf())
+                                .unwrap_or_default();
+                            dest.join(dir).join(dest_name).normalize()
+                        } else {
+                            dest.join(dest_name).normalize()
+                        };
                         if self.resources.contains_key(&dest_file) {
-                            warn!(
-                                "resource `{}` overwrites previous resource at `{}`",
-                                path.to_string_lossy(),
-                                dest_file
-                            );
+                            Diagnostic::warning(
+                                "resource-overwrite",
+                                format!(
+                                    "resource `{}` overwrites previous resource at `{}`",
+                                    path.to_string_lossy(),
+                                    dest_file
+                                ),
+                            )
+                            .file(source_path)
+                            .emit();
                             continue;
                         }
 
-                        self.resources.insert(dest_file, path);
+                        self.resources.insert(dest_file, Resource::File(path));
                     }
                 },
             }
         }
+
+        if matched == 0 {
+            match self.options.empty_glob {
+                EmptyGlobPolicy::Warn => Diagnostic::warning(
+                    "resource-empty-glob",
+                    format!("resource pattern `{}` did not match any files", pattern),
+                )
+                .file(source_path)
+                .location(location)
+                .emit(),
+                EmptyGlobPolicy::Error => {
+                    return Err(PreprocessError::EmptyResourceGlob(
+                        source_path.to_path_buf(),
+                        location.clone(),
+                        pattern.to_string(),
+                    ))
+                }
+            }
+        }
+
+        Ok(())
     }
 
     fn feed_directive_unknown(&mut self, name: &parser::Input, contents: &parser::Input) {
-        self.parts.push(format!("; #{name}{contents}\n"));
+        self.push(format!("; #{name}{contents}\n"));
     }
 }
 
+/// Match `text` against a resource pattern, returning the text matched by each `{name}`
+/// placeholder. `*` and placeholders don't match `/`, but `**` does, and `**/` may match nothing.
+fn match_placeholders(pattern: &str, text: &str) -> Option<HashMap<String, String>> {
+    fn inner(pattern: &[char], text: &[char], captures: &mut Vec<(String, String)>) -> bool {
+        match pattern.first() {
+            None => text.is_empty(),
+            Some('*') if pattern.get(1) == Some(&'*') => {
+                let rest = &pattern[2..];
+                // like in globs, `**/` also matches no folders at all
+                (rest.first() == Some(&'/') && inner(&rest[1..], text, captures))
+                    || (0..=text.len()).any(|i| inner(rest, &text[i..], captures))
+            }
+            Some('*') => {
+                let rest = &pattern[1..];
+                let max = text.iter().position(|x| *x == '/').unwrap_or(text.len());
+                (0..=max).any(|i| inner(rest, &text[i..], captures))
+            }
+            Some('{') => {
+                let Some(end) = pattern.iter().position(|x| *x == '}') else {
+                    return text.first() == Some(&'{')
+                        && inner(&pattern[1..], &text[1..], captures);
+                };
+                let name: String = pattern[1..end].iter().collect();
+                let rest = &pattern[end + 1..];
+                let max = text.iter().position(|x| *x == '/').unwrap_or(text.len());
+                for i in 0..=max {
+                    captures.push((name.clone(), text[..i].iter().collect()));
+                    if inner(rest, &text[i..], captures) {
+                        return true;
+                    }
+                    captures.pop();
+                }
+                false
+            }
+            Some('?') => {
+                !text.is_empty() && text[0] != '/' && inner(&pattern[1..], &text[1..], captures)
+            }
+            Some('[') => match pattern.iter().position(|x| *x == ']') {
+                // character classes are treated as matching any single character
+                Some(end) => !text.is_empty() && inner(&pattern[end + 1..], &text[1..], captures),
+                None => text.first() == Some(&'[') && inner(&pattern[1..], &text[1..], captures),
+            },
+            Some(c) => text.first() == Some(c) && inner(&pattern[1..], &text[1..], captures),
+        }
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let mut captures = Vec::new();
+    if inner(&pattern, &text, &mut captures) {
+        Some(captures.into_iter().collect())
+    } else {
+        None
+    }
+}
+
+/// Get the destination name of a resource by filling in a rename template with the placeholders
+/// matched from the resource's path
+fn rename_resource(pattern: &str, relpath: &str, template: &str) -> Option<String> {
+    let captures = match_placeholders(pattern, relpath)?;
+    let mut result = template.to_string();
+    for (name, value) in captures {
+        result = result.replace(&format!("{{{name}}}"), &value);
+    }
+    Some(result)
+}
+
 fn _preprocess(mut builder: &mut ThemeBuilder, path: &Path) -> Result {
     let text = read(&path)?;
+    cache::record(&builder.lua, |x| x.file(path, text.as_bytes()));
     let contents = parse_rtconfig(&path, &text)?;
 
+    let outer_location = std::mem::replace(&mut builder.location, SourceLocation::new(path, 1));
+
     for content in &contents {
         if let RtconfigContent::Directive(Directive::Include(include_relpath)) = content {
             let include_path = include_relpath.to_path(path.parent().unwrap());
@@ -360,14 +870,20 @@ fn _preprocess(mut builder: &mut ThemeBuilder, path: &Path) -> Result {
         }
     }
 
+    builder.location = outer_location;
+
     Ok(())
 }
 
 pub fn preprocess(
     path: &Path,
     globals: Option<HashMap<String, String>>,
-) -> Result<(String, Ini, ResourceMap)> {
-    let mut builder = ThemeBuilder::new();
+    options: &PreprocessOptions,
+) -> Result<PreprocessOutput> {
+    let mut builder = ThemeBuilder::with_options(options.clone());
+    builder.lua.set_app_data(interpreter::ProjectRoot(
+        path.parent().unwrap_or(Path::new("")).to_path_buf(),
+    ));
 
     if let Some(globals) = globals {
         let table = builder.lua.globals();
@@ -378,18 +894,26 @@ pub fn preprocess(
         }
     };
 
-    _preprocess(&mut builder, &path)?;
+    let (result, diagnostics) = diagnostics::record(|| _preprocess(&mut builder, path));
+    result?;
 
-    Ok((
-        builder.rtconfig(),
-        builder.reapertheme().clone(),
-        builder.resources().clone(),
-    ))
+    Ok(PreprocessOutput {
+        rtconfig: builder.rtconfig(),
+        reapertheme: builder.reapertheme().clone(),
+        resources: builder.resources().clone(),
+        source_map: builder.source_map(),
+        inputs: builder
+            .lua
+            .remove_app_data::<cache::Inputs>()
+            .unwrap_or_default(),
+        diagnostics,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempDir;
     use indoc::indoc;
 
     fn feed(builder: &mut ThemeBuilder, content: RtconfigContent) {
@@ -398,7 +922,7 @@ mod tests {
 
     #[test]
     fn test_01() {
-        let mut builder = ThemeBuilder::new();
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
 
         feed(
             &mut builder,
@@ -430,15 +954,323 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_source_map() {
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let path: &Path = "a.rtconfig.txt".as_ref();
+        builder.location = SourceLocation::new(path, 1);
+
+        let text = indoc! {r#"
+            set a [1]
+
+            #{"x\ny"} ; comment
+            #foo bar
+            set b #{
+              2
+            } [3]
+            set c [4]"#};
+        for content in parse_rtconfig(path, text).unwrap() {
+            builder.feed(&content, path).unwrap();
+        }
+
+        assert_eq!(
+            builder.rtconfig(),
+            "set a [1]\n\nx\ny ; comment\n; #foo bar\nset b 2 [3]\nset c [4]"
+        );
+        let lines: Vec<u32> = builder.source_map().iter().map(|x| x.line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 3, 4, 5, 8]);
+    }
+
+    #[test]
+    fn test_serialise_table() {
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let path: &Path = "a.rtconfig.txt".as_ref();
+        let text = indoc! {r#"
+            set a #{{1, 2.5, "x", {true}}}
+            set b #{gradient(rgb(0, 0, 0), rgb(0, 0, 255), 2)}
+            set c #{{blend("add", 0), decode_blend(-1)}}
+            set d #{{toggle(rgb(1, 0, 0), true), toggle(rgba(1, 0, 0, 2), false)}}"#};
+        for content in parse_rtconfig(path, text).unwrap() {
+            builder.feed(&content, path).unwrap();
+        }
+        assert_eq!(
+            builder.rtconfig(),
+            "set a 1 2.5 x true\nset b 0 16711680\nset c 131073 -1\nset d 1 -4261412863"
+        );
+
+        for text in [
+            "set a #{{x = 1}}",
+            "set a #{{1, print}}",
+            "set a #{print}",
+            "set a #{fill_image(1, 1, rgb(0, 0, 0))}",
+            "set a #{{fill_image(1, 1, rgb(0, 0, 0))}}",
+            "set a #{(function() local t = {1} t[2] = t return t end)()}",
+            "set a #{(function() local t = {1} t[2] = {t} return t end)()}",
+        ] {
+            let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+            let contents = parse_rtconfig(path, text).unwrap();
+            assert!(contents.iter().any(|x| builder.feed(x, path).is_err()));
+        }
+
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let contents = parse_rtconfig(path, "set a #{fill_image(1, 1, rgb(0, 0, 0))}").unwrap();
+        let err = contents
+            .iter()
+            .find_map(|x| builder.feed(x, path).err())
+            .unwrap();
+        assert!(err.to_string().contains("images can't be serialised"));
+
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let text = "set a #{(function() local t = {1} t[2] = {t} return t end)()}";
+        let err = parse_rtconfig(path, text)
+            .unwrap()
+            .iter()
+            .find_map(|x| builder.feed(x, path).err())
+            .unwrap();
+        assert!(err.to_string().contains("contains itself"));
+
+        // the same table can appear more than once, as long as it doesn't contain itself
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let text = "set a #{(function() local t = {1, 2} return {t, t} end)()}";
+        for content in parse_rtconfig(path, text).unwrap() {
+            builder.feed(&content, path).unwrap();
+        }
+        assert_eq!(builder.rtconfig(), "set a 1 2 1 2");
+    }
+
+    #[test]
+    fn test_value_type() {
+        let root = TempDir::new("value-type");
+        let path = root.join("colors.ReaperTheme");
+        fs::write(
+            &path,
+            indoc! {r#"
+                [color theme]
+                col_tr1_bg=#{blend("add", 0.5)}
+                playcursor_color=#{blend("add", 0.5)}
+                selcol_tr1_bg=#{rgb(1, 2, 3)}
+                midi_itemctl_mode=#{rgb(1, 2, 3)}
+                col_tl_bgsel=#{blend("add", 0.5)}
+                timesel_drawmode=#{rgba(1, 2, 3, 4)}
+                group_12=#{blend("add", 0.5)}
+                col_unknown_mode=#{blend("add", 0.5)}
+                col_unknown=#{blend("add", 0.5)}
+
+                [other section]
+                col_tr1_bg=#{blend("add", 0.5)}
+            "#},
+        )
+        .unwrap();
+
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let (result, diagnostics) = diagnostics::record(|| builder.import_config(&path));
+        result.unwrap();
+        let messages: Vec<String> = diagnostics.into_iter().map(|x| x.message).collect();
+        assert_eq!(
+            messages,
+            [
+                "`col_tr1_bg` expects a color, but was given a blend value",
+                "`playcursor_color` expects a color, but was given a blend value",
+                "`midi_itemctl_mode` expects a blend value, but was given a color",
+                "`col_tl_bgsel` expects a color, but was given a blend value",
+                "`timesel_drawmode` expects a blend value, but was given a color",
+                "`group_12` expects a color, but was given a blend value",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_resource() {
+        assert_eq!(
+            rename_resource("src/knob_{n}.png", "src/knob_12.png", "knob{n}.png"),
+            Some("knob12.png".into())
+        );
+        assert_eq!(
+            rename_resource(
+                "{kind}/*_{state}.png",
+                "tcp/mute_on.png",
+                "{kind}_mute_{state}.png"
+            ),
+            Some("tcp_mute_on.png".into())
+        );
+        assert_eq!(
+            rename_resource("**/{name}.png", "a/b/c.png", "{name}_2x.png"),
+            Some("c_2x.png".into())
+        );
+        assert_eq!(
+            rename_resource("**/{n}.png", "c.png", "{n}_2x.png"),
+            Some("c_2x.png".into())
+        );
+        assert_eq!(
+            rename_resource("images/**/{n}.png", "images/knob.png", "x_{n}.png"),
+            Some("x_knob.png".into())
+        );
+        assert_eq!(
+            rename_resource("src/{n}.png", "other/1.png", "{n}.png"),
+            None
+        );
+        assert_eq!(rename_resource("{n}.png", "a/1.png", "{n}.png"), None);
+    }
+
+    #[test]
+    fn test_resource_preserve() {
+        let root = TempDir::new("resource-preserve");
+        for dir in ["images/150", "images/200"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("knob.png"), "").unwrap();
+        }
+        let source_path = root.join("index.rtconfig.txt");
+
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let text = r#"#resource "theme": "images/**/*.png" preserve"#;
+        for content in parse_rtconfig(&source_path, text).unwrap() {
+            builder.feed(&content, &source_path).unwrap();
+        }
+
+        let mut keys: Vec<String> = builder.resources().keys().map(|x| x.to_string()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["theme/150/knob.png", "theme/200/knob.png"]);
+    }
+
+    #[test]
+    fn test_resource_literal_braces() {
+        let root = TempDir::new("resource-literal-braces");
+        fs::create_dir_all(root.join("icons")).unwrap();
+        for name in ["{old}.png", "new.png"] {
+            fs::write(root.join("icons").join(name), "").unwrap();
+        }
+        let source_path = root.join("index.rtconfig.txt");
+
+        // braces are only placeholders when the files are renamed
+        let resources = |text: &str| {
+            let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+            for content in parse_rtconfig(&source_path, text).unwrap() {
+                builder.feed(&content, &source_path).unwrap();
+            }
+            let mut keys: Vec<String> = builder.resources().keys().map(|x| x.to_string()).collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(
+            resources(r#"#resource "icons/{old}.png""#),
+            vec!["{old}.png"]
+        );
+        assert_eq!(
+            resources(r#"set a #{resource('icons/{old}.png')}"#),
+            vec!["{old}.png"]
+        );
+        assert_eq!(
+            resources(r#"#resource "icons/{old}.png" as "x_{old}.png""#),
+            vec!["x_new.png", "x_{old}.png"]
+        );
+    }
+
+    #[test]
+    fn test_resource_from_included_script() {
+        let root = TempDir::new("resource-included-script");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(root.join("lib/res.lua"), "resource('*.png')").unwrap();
+        fs::write(root.join("lib/knob.png"), "").unwrap();
+        fs::write(root.join("bg.png"), "").unwrap();
+        let source_path = root.join("index.rtconfig.txt");
+
+        // patterns in an included script are relative to the script
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        for content in parse_rtconfig(&source_path, r#"#include "lib/res.lua""#).unwrap() {
+            builder.feed(&content, &source_path).unwrap();
+        }
+        let keys: Vec<String> = builder.resources().keys().map(|x| x.to_string()).collect();
+        assert_eq!(keys, vec!["knob.png"]);
+    }
+
+    #[test]
+    fn test_resource_invalid_glob() {
+        let root = TempDir::new("resource-invalid-glob");
+        let source_path = root.join("index.rtconfig.txt");
+        assert!(
+            parse_rtconfig(&source_path, r#"#resource "knob_*{n}.png" as "k{n}.png""#).is_err()
+        );
+
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let text = "set a #{resource('knob_*{n}.png', {rename = 'k{n}.png'})}";
+        let contents = parse_rtconfig(&source_path, text).unwrap();
+        assert!(contents
+            .iter()
+            .any(|x| builder.feed(x, &source_path).is_err()));
+
+        // the folder of the file is part of the pattern
+        let source_path = root.join("bad[dir").join("index.rtconfig.txt");
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        let contents = parse_rtconfig(&source_path, r#"#resource "*.png""#).unwrap();
+        let err = contents
+            .iter()
+            .find_map(|x| builder.feed(x, &source_path).err())
+            .unwrap();
+        assert!(
+            matches!(err, PreprocessError::InvalidResourceGlob(..)),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn test_resource_empty_glob() {
+        let root = TempDir::new("resource-empty-glob");
+        let source_path = root.join("index.rtconfig.txt");
+        let text = "set a [1]\n#resource \"missing/*.png\"\nset b #{resource('*.png')}";
+
+        // warn by default
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        for content in parse_rtconfig(&source_path, text).unwrap() {
+            builder.feed(&content, &source_path).unwrap();
+        }
+
+        // error when strict
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        builder.options = PreprocessOptions::default().empty_glob(EmptyGlobPolicy::Error);
+        let contents = parse_rtconfig(&source_path, text).unwrap();
+        let err = contents
+            .iter()
+            .map(|x| builder.feed(x, &source_path))
+            .find_map(|x| x.err())
+            .unwrap();
+        let PreprocessError::EmptyResourceGlob(_, location, pattern) = err else {
+            panic!("expected EmptyResourceGlob, got {:?}", err)
+        };
+        assert_eq!(pattern, "missing/*.png");
+        assert_eq!(location.line, 2);
+
+        // resource() in an expression reports the expression's location
+        let mut builder = ThemeBuilder::with_options(PreprocessOptions::default());
+        builder.options = PreprocessOptions::default().empty_glob(EmptyGlobPolicy::Error);
+        let err = builder
+            .feed(&contents[contents.len() - 1], &source_path)
+            .unwrap_err();
+        let PreprocessError::EmptyResourceGlob(_, location, pattern) = err else {
+            panic!("expected EmptyResourceGlob, got {:?}", err)
+        };
+        assert_eq!(pattern, "*.png");
+        assert_eq!((location.line, location.column_utf8), (3, 9));
+    }
+
     #[test]
     fn test_02() {
         crate::setup_logging();
 
-        match preprocess(r"test\test.rtconfig.txt".as_ref(), None) {
-            Ok((rtconfig, reapertheme, res)) => {
+        match preprocess(
+            r"test\test.rtconfig.txt".as_ref(),
+            None,
+            &PreprocessOptions::default(),
+        ) {
+            Ok(PreprocessOutput {
+                rtconfig,
+                reapertheme,
+                resources: res,
+                ..
+            }) => {
                 let mut new_res: HashMap<String, String> = HashMap::new();
                 for (k, v) in res.iter() {
-                    new_res.insert(k.to_string(), v.to_string_lossy().to_string());
+                    let path = v.path().unwrap_or("<generated>".as_ref());
+                    new_res.insert(k.to_string(), path.to_string_lossy().to_string());
                 }
 
                 fs::write("out.rtconfig.txt", rtconfig).unwrap();
@@ -0,0 +1,312 @@
+use std::collections::BTreeSet;
+
+use relative_path::RelativePath;
+
+use crate::{diagnostics::Diagnostic, theme::ResourceMap};
+
+/// Folders that REAPER loads scaled copies of images from
+const SCALE_FOLDERS: [&str; 2] = ["150", "200"];
+
+/// Standard images that REAPER loads for an element, when the element is used in the rtconfig.
+/// Only the mute, solo, phase, record arm and record monitor buttons are known.
+const ELEMENT_IMAGES: [(&str, &[&str]); 10] = [
+    ("tcp.mute", &["track_mute_on", "track_mute_off"]),
+    ("tcp.solo", &["track_solo_on", "track_solo_off"]),
+    ("tcp.phase", &["track_phase_norm", "track_phase_inv"]),
+    ("tcp.recarm", &["track_recarm_on", "track_recarm_off"]),
+    ("tcp.recmon", &["track_monitor_on", "track_monitor_off"]),
+    ("mcp.mute", &["mcp_mute_on", "mcp_mute_off"]),
+    ("mcp.solo", &["mcp_solo_on", "mcp_solo_off"]),
+    ("mcp.phase", &["mcp_phase_norm", "mcp_phase_inv"]),
+    ("mcp.recarm", &["mcp_recarm_on", "mcp_recarm_off"]),
+    ("mcp.recmon", &["mcp_monitor_on", "mcp_monitor_off"]),
+];
+
+/// Prefixes of standard images that REAPER loads regardless of the rtconfig, e.g. for the default
+/// track panel and mixer layouts
+const GLOBAL_IMAGE_PREFIXES: [&str; 13] = [
+    "gen_",
+    "transport_",
+    "toolbar_",
+    "table_",
+    "piano_",
+    "midi_",
+    "scrollbar_",
+    "meter_",
+    "item_",
+    "envcp_",
+    "global_",
+    "tcp_",
+    "mcp_",
+];
+
+/// Image names used by a rtconfig
+#[derive(Debug, Default, PartialEq)]
+struct References {
+    /// Images named explicitly, e.g. `"knob.png"`
+    explicit: BTreeSet<String>,
+    /// Words in the rtconfig code; an image with the same name is considered used
+    words: BTreeSet<String>,
+    /// Folders used by `layout "name" "folder"`
+    layout_folders: BTreeSet<String>,
+    /// Standard images implied by elements in use
+    implied: Vec<&'static [&'static str]>,
+}
+
+/// Remove a `;` comment from a line, ignoring `;` inside quoted strings
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+/// Split a line into quoted strings and the remaining code
+fn split_strings(line: &str) -> (Vec<&str>, String) {
+    let mut strings = Vec::new();
+    let mut code = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('"') {
+        code.push_str(&rest[..start]);
+        match rest[start + 1..].find('"') {
+            Some(end) => {
+                strings.push(&rest[start + 1..start + 1 + end]);
+                rest = &rest[start + 1 + end + 1..];
+            }
+            None => {
+                rest = &rest[start + 1..];
+            }
+        }
+        code.push(' ');
+    }
+    code.push_str(rest);
+    (strings, code)
+}
+
+fn find_references(rtconfig: &str) -> References {
+    let mut refs = References::default();
+
+    for line in rtconfig.lines() {
+        let line = strip_comment(line);
+        let (strings, code) = split_strings(line);
+
+        for string in &strings {
+            if string.to_ascii_lowercase().ends_with(".png") {
+                refs.explicit
+                    .insert(RelativePath::new(string).normalize().to_string());
+            }
+        }
+        if code.trim_start().to_ascii_lowercase().starts_with("layout") {
+            if let Some(folder) = strings.get(1) {
+                refs.layout_folders
+                    .insert(RelativePath::new(folder).normalize().to_string());
+            }
+        }
+
+        for word in code.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')) {
+            if !word.is_empty() {
+                refs.words.insert(word.to_string());
+            }
+        }
+    }
+
+    for (element, images) in ELEMENT_IMAGES {
+        let used = refs
+            .words
+            .iter()
+            .any(|x| x == element || x.starts_with(&format!("{element}.")));
+        if used {
+            refs.implied.push(images);
+        }
+    }
+
+    refs
+}
+
+/// Split a packed image path into its folder (excluding scale folders) and name without extension,
+/// e.g. `layoutA/200/knob.png` becomes `("layoutA", "knob")`
+fn image_name(path: &RelativePath) -> (String, String) {
+    let stem = path.file_stem().unwrap_or_default().to_string();
+    let mut folder = path
+        .parent()
+        .map(|x| x.to_relative_path_buf())
+        .unwrap_or_default();
+    if SCALE_FOLDERS.contains(&folder.file_name().unwrap_or_default()) {
+        folder = folder
+            .parent()
+            .map(|x| x.to_relative_path_buf())
+            .unwrap_or_default();
+    }
+    (folder.to_string(), stem)
+}
+
+/// Compare the images referenced by a rtconfig with the packed resources. Reports images that are
+/// referenced but not packed, and with `report_unused`, packed images that nothing seems to use.
+/// REAPER loads far more standard images by name than are listed here, so unused images are only a
+/// hint: images of elements other than those in [`ELEMENT_IMAGES`] are only found by name. The
+/// images in [`ELEMENT_IMAGES`] and images starting with one of [`GLOBAL_IMAGE_PREFIXES`] are
+/// always considered used, since REAPER's default layouts load them.
+pub fn check(rtconfig: &str, resources: &ResourceMap, report_unused: bool) -> Vec<Diagnostic> {
+    let refs = find_references(rtconfig);
+    let mut diagnostics = Vec::new();
+
+    let images: Vec<&RelativePath> = resources
+        .keys()
+        .map(|x| x.as_relative_path())
+        .filter(|x| {
+            x.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+        })
+        .collect();
+    let packed: BTreeSet<(String, String)> = images.iter().map(|x| image_name(x)).collect();
+    let packed_names: BTreeSet<&str> = packed.iter().map(|(_, name)| name.as_str()).collect();
+
+    // referenced but missing
+    for explicit in &refs.explicit {
+        let (folder, name) = image_name(RelativePath::new(explicit));
+        if !packed.contains(&(folder, name)) {
+            diagnostics.push(Diagnostic::warning(
+                "image-missing",
+                format!("image `{explicit}` is referenced in the rtconfig but not packed"),
+            ));
+        }
+    }
+    for family in &refs.implied {
+        // only report incomplete sets, a theme may rely on the default images on purpose
+        let present = family.iter().filter(|x| packed_names.contains(*x)).count();
+        if present > 0 && present < family.len() {
+            for name in family.iter().filter(|x| !packed_names.contains(*x)) {
+                diagnostics.push(Diagnostic::warning(
+                    "image-missing",
+                    format!(
+                        "image `{name}.png` is missing, but other images of its element are packed"
+                    ),
+                ));
+            }
+        }
+    }
+
+    if !report_unused {
+        return diagnostics;
+    }
+
+    // packed but unused
+    let mut unused: Vec<&RelativePath> = images
+        .into_iter()
+        .filter(|path| {
+            let (folder, name) = image_name(path);
+            let in_layout = !folder.is_empty() && refs.layout_folders.contains(&folder);
+            let explicit = refs
+                .explicit
+                .iter()
+                .any(|x| image_name(RelativePath::new(x)) == (folder.clone(), name.clone()));
+            let element = ELEMENT_IMAGES
+                .iter()
+                .any(|(_, family)| family.contains(&name.as_str()));
+            let global = GLOBAL_IMAGE_PREFIXES.iter().any(|x| name.starts_with(x));
+            !(in_layout || explicit || element || global || refs.words.contains(&name))
+        })
+        .collect();
+    unused.sort();
+    for path in unused {
+        diagnostics.push(Diagnostic::warning(
+            "image-unused",
+            format!("image `{path}` is packed but not referenced by the rtconfig"),
+        ));
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theme::Resource;
+    use indoc::indoc;
+    use relative_path::RelativePathBuf;
+
+    fn resources(paths: &[&str]) -> ResourceMap {
+        paths
+            .iter()
+            .map(|x| (RelativePathBuf::from(*x), Resource::File(x.into())))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_references() {
+        let refs = find_references(indoc! {r#"
+            ; "commented.png"
+            Layout "A" "layout_a"
+            set tcp.mute.color [1 2 3]
+            set tcp.custom "images/knob.png" ; "also_commented.png"
+            set tcp.other "a;b.png" ; "commented;again.png"
+        "#});
+        assert_eq!(
+            refs.explicit,
+            BTreeSet::from(["a;b.png".to_string(), "images/knob.png".to_string()])
+        );
+        assert_eq!(
+            refs.layout_folders,
+            BTreeSet::from(["layout_a".to_string()])
+        );
+        assert_eq!(refs.implied, vec![ELEMENT_IMAGES[0].1]);
+        assert!(refs.words.contains("tcp.custom"));
+        assert!(!refs.words.contains("A"));
+    }
+
+    #[test]
+    fn test_check() {
+        let rtconfig = indoc! {r#"
+            Layout "A" "layout_a"
+            set tcp.mute [0 0 20 20]
+            set tcp.custom "images/knob.png"
+            set tcp.other "images/missing.png"
+            set my_image [0 0]
+        "#};
+        let resources = resources(&[
+            "images/knob.png",
+            "images/200/knob.png",
+            "layout_a/anything.png",
+            "track_mute_on.png",
+            "150/track_mute_on.png",
+            "gen_play.png",
+            "my_image.png",
+            "mcp_mute_on.png",
+            "mcp_solo_on.png",
+            "150/track_solo_on.png",
+            "mcp_vol_knob.png",
+            "unused.png",
+            "readme.txt",
+        ]);
+
+        let messages = |report_unused: bool| {
+            let mut messages: Vec<String> = check(rtconfig, &resources, report_unused)
+                .iter()
+                .map(|x| x.message.clone())
+                .collect();
+            messages.sort();
+            messages
+        };
+        assert_eq!(
+            messages(true),
+            vec![
+                "image `images/missing.png` is referenced in the rtconfig but not packed",
+                "image `track_mute_off.png` is missing, but other images of its element are packed",
+                "image `unused.png` is packed but not referenced by the rtconfig",
+            ]
+        );
+        // unused images are only reported when asked for
+        assert_eq!(
+            messages(false),
+            vec![
+                "image `images/missing.png` is referenced in the rtconfig but not packed",
+                "image `track_mute_off.png` is missing, but other images of its element are packed",
+            ]
+        );
+    }
+}
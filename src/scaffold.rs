@@ -0,0 +1,178 @@
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::{diagnostics::Diagnostic, images::Image};
+
+const INDEX: &str = r#"; Build with:
+;   reaper-theme-packer index.rtconfig.txt <name>.ReaperThemeZip
+version 7
+use_pngs 1
+
+#include "constants.lua"
+#include "colors.ini"
+
+; images/ is added at 100%, images/150/ and images/200/ for HiDPI screens
+#resource "images/**/*.png" preserve
+
+set tcp.trackidx.color [#{colors.text:arr()}]
+"#;
+
+const CONSTANTS: &str = r#"-- information about the theme, from the manifest
+theme = load_toml("theme.toml")
+resource_text("readme.txt", theme.name .. " " .. theme.version .. "\n" .. theme.description .. "\n")
+
+-- colors shared by the rtconfig and colors.ini
+colors = {
+    background = rgb(43, 45, 48),
+    panel = rgb(54, 57, 61),
+    text = rgb(220, 222, 225),
+    shadow = rgb(20, 21, 23),
+    highlight = rgb(80, 84, 90),
+    accent = rgb(86, 156, 214),
+    selection = rgb(62, 82, 105),
+}
+"#;
+
+const COLORS: &str = r#"[REAPER]
+ui_img=#{THEME_NAME}
+
+[color theme]
+; main window
+col_main_bg2=#{colors.background}
+col_main_text2=#{colors.text}
+col_main_textshadow=#{colors.shadow}
+col_main_3dhl=#{colors.highlight}
+col_main_3dsh=#{colors.shadow}
+col_main_resize2=#{colors.background}
+col_main_editbk=#{colors.panel}
+; tracks
+col_tcp_text=#{colors.text}
+col_tcp_textsel=#{colors.text}
+col_seltrack=#{colors.accent}
+col_tr1_bg=#{colors.panel}
+col_tr2_bg=#{colors.panel}
+selcol_tr1_bg=#{colors.selection}
+selcol_tr2_bg=#{colors.selection}
+; arrange view
+col_arrangebg=#{colors.background}
+col_tl_fg=#{colors.text}
+col_tl_bg=#{colors.panel}
+col_cursor=#{colors.accent}
+playcursor_color=#{colors.accent}
+col_mi_label=#{colors.text}
+"#;
+
+/// Background of the track panel, the same as `colors.panel`
+const TCP_BG: [u8; 4] = [54, 57, 61, 255];
+
+#[derive(Error, Debug)]
+pub enum InitError {
+    #[error("the path `{0}` already exists")]
+    PathExistsError(PathBuf),
+    #[error("failed to write `{0}`: {1}")]
+    WriteError(PathBuf, std::io::Error),
+}
+
+impl From<&InitError> for Diagnostic {
+    fn from(err: &InitError) -> Self {
+        match err {
+            InitError::PathExistsError(path) => {
+                Diagnostic::error("path-exists", "the path already exists").file(path)
+            }
+            InitError::WriteError(path, inner) => {
+                Diagnostic::error("write-error", format!("failed to write file: {}", inner))
+                    .file(path)
+            }
+        }
+    }
+}
+
+fn manifest(name: &str) -> String {
+    format!(
+        "name = {}\nversion = \"0.1.0\"\nauthor = \"\"\ndescription = \"A theme for REAPER\"\n",
+        toml::Value::String(name.to_string())
+    )
+}
+
+/// Write a new theme project to a folder: an rtconfig, a Lua file with the colors, a .ini with the
+/// main `[color theme]` keys, the image folders, and a `theme.toml` manifest. Nothing is written if
+/// any of the files already exist. Returns the paths of the files written.
+pub fn init(dir: &Path, name: &str) -> Result<Vec<PathBuf>, InitError> {
+    let files: Vec<(PathBuf, Vec<u8>)> = vec![
+        (dir.join("index.rtconfig.txt"), INDEX.into()),
+        (dir.join("constants.lua"), CONSTANTS.into()),
+        (dir.join("colors.ini"), COLORS.into()),
+        (dir.join("theme.toml"), manifest(name).into()),
+        (
+            dir.join("images").join("tcp_bg.png"),
+            Image::fill(20, 20, TCP_BG)
+                .expect("the background image is small")
+                .encode(),
+        ),
+    ];
+
+    if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+        return Err(InitError::PathExistsError(path.clone()));
+    }
+
+    for folder in ["150", "200"] {
+        let path = dir.join("images").join(folder);
+        std::fs::create_dir_all(&path).map_err(|err| InitError::WriteError(path, err))?;
+    }
+    for (path, contents) in files.iter() {
+        std::fs::write(path, contents).map_err(|err| InitError::WriteError(path.clone(), err))?;
+    }
+
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        preprocess::{preprocess, EmptyGlobPolicy, PreprocessOptions},
+        testing::TempDir,
+    };
+
+    #[test]
+    fn test_init() {
+        let parent = TempDir::new("init");
+        let dir = parent.join("theme");
+
+        let files = init(&dir, "My \"Theme\"").unwrap();
+        assert_eq!(files.len(), 5);
+        assert!(dir.join("images/150").is_dir());
+
+        // the skeleton builds without warnings about missing images
+        let globals = HashMap::from([("THEME_NAME".to_string(), "MyTheme".to_string())]);
+        let output = preprocess(
+            &dir.join("index.rtconfig.txt"),
+            Some(globals),
+            &PreprocessOptions::default().empty_glob(EmptyGlobPolicy::Error),
+        )
+        .unwrap();
+        assert!(output
+            .rtconfig
+            .contains("set tcp.trackidx.color [220 222 225]"));
+        assert_eq!(
+            output.reapertheme.get_from(Some("REAPER"), "ui_img"),
+            Some("MyTheme")
+        );
+        assert_eq!(
+            output
+                .reapertheme
+                .get_from(Some("color theme"), "col_tr1_bg"),
+            Some("4012342")
+        );
+        let mut keys: Vec<String> = output.resources.keys().map(|x| x.to_string()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["readme.txt", "tcp_bg.png"]);
+
+        // existing projects aren't overwritten
+        let err = init(&dir, "MyTheme").unwrap_err();
+        assert!(matches!(err, InitError::PathExistsError(_)));
+    }
+}
@@ -1,15 +1,54 @@
-use log::warn;
 use std::{
-    collections::HashMap,
-    io::Write,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    io::{Cursor, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
 };
 
+use clap::ValueEnum;
 use ini::Ini;
-use relative_path::RelativePathBuf;
+use log::info;
+use relative_path::{RelativePath, RelativePathBuf};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-pub type ResourceMap = HashMap<RelativePathBuf, PathBuf>;
+use crate::{
+    cache::{self, Cache},
+    diagnostics::Diagnostic,
+    images,
+};
+
+/// Contents of a file in the theme archive
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resource {
+    /// A file on disk, read when the theme is built
+    File(PathBuf),
+    /// Contents generated while preprocessing, e.g. an image made in Lua code or a readme
+    Generated(Vec<u8>),
+}
+
+impl Resource {
+    pub fn read(&self) -> std::io::Result<Cow<'_, [u8]>> {
+        match self {
+            Resource::File(path) => std::fs::read(path).map(Cow::Owned),
+            Resource::Generated(bytes) => Ok(Cow::Borrowed(bytes)),
+        }
+    }
+
+    /// The path of the file on disk, if the resource isn't generated
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Resource::File(path) => Some(path),
+            Resource::Generated(_) => None,
+        }
+    }
+}
+
+pub type ResourceMap = HashMap<RelativePathBuf, Resource>;
 
 pub struct Theme {
     name: String,
@@ -30,12 +69,7 @@ impl Default for Theme {
 }
 
 impl Theme {
-    pub fn new(
-        name: &str,
-        rtconfig: &str,
-        config: Ini,
-        resources: HashMap<RelativePathBuf, PathBuf>,
-    ) -> Self {
+    pub fn new(name: &str, rtconfig: &str, config: Ini, resources: ResourceMap) -> Self {
         let name = name.to_string();
         let rtconfig = rtconfig.to_string();
 
@@ -55,13 +89,321 @@ impl Theme {
     }
 }
 
+/// Problems found in a PNG file and its shape, saved in the cache under the key of its compressed
+/// entry. The diagnostics don't have a file, since the same contents may come from another path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageCheck {
+    pub diagnostics: Vec<Diagnostic>,
+    pub shape: Option<images::Shape>,
+}
+
+/// Decode a `.png` resource and check its format and pink pixel border. Returns its shape, to check
+/// the size of scaled images in the `150` and `200` folders once every image is decoded.
+fn check_image(bytes: &[u8]) -> ImageCheck {
+    match images::Image::decode(bytes) {
+        Err(err) => ImageCheck {
+            diagnostics: vec![Diagnostic::warning(
+                "png-invalid",
+                format!("not a valid PNG image: {err}"),
+            )],
+            shape: None,
+        },
+        Ok((image, format)) => {
+            let mut diagnostics = Vec::new();
+            if let Some(problem) = images::format_problems(&format) {
+                diagnostics.push(Diagnostic::warning("png-format", problem));
+            }
+            for problem in images::border_problems(&image) {
+                diagnostics.push(Diagnostic::warning("png-pink-border", problem));
+            }
+            ImageCheck {
+                diagnostics,
+                shape: Some(image.shape()),
+            }
+        }
+    }
+}
+
+/// Check the size of images in the `150` and `200` folders against their base images
+fn scale_problems(
+    resources: &[(&RelativePathBuf, &Resource)],
+    shapes: &HashMap<RelativePathBuf, images::Shape>,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for (archive_path, resource) in resources {
+        let Some((base_path, scale)) = images::scaled_base(archive_path) else {
+            continue;
+        };
+        let (Some(base), Some(scaled)) = (
+            shapes.get(&base_path.normalize()),
+            shapes.get(&archive_path.normalize()),
+        ) else {
+            continue;
+        };
+        if let Some(problem) = images::scale_problem(base, scaled, scale) {
+            diagnostics.push(
+                Diagnostic::warning("png-scale", format!("{problem}, compared to `{base_path}`"))
+                    .file(&diagnostic_path(archive_path, resource)),
+            );
+        }
+    }
+    diagnostics
+}
+
+/// A resource compressed into its own archive, ready to be copied into the theme archive
+struct PreparedResource {
+    path: RelativePathBuf,
+    /// a ZIP archive containing only this resource
+    data: Vec<u8>,
+    /// size before and after optimizing, for PNG files when optimizing is enabled
+    optimized: Option<(usize, usize)>,
+    diagnostics: Vec<Diagnostic>,
+    /// shape of a PNG file, when validating images
+    shape: Option<images::Shape>,
+    /// key of the compressed entry in the cache, when caching is enabled
+    cache_key: Option<String>,
+    /// whether the compressed entry was reused from the cache
+    cached: bool,
+}
+
+/// Load a compressed entry from the cache, with the uncompressed size of its file. Damaged
+/// entries are ignored.
+fn cached_entry(cache: &Cache, key: &str) -> Option<(Vec<u8>, usize)> {
+    let data = cache.load_entry(key)?;
+    let size = zip::ZipArchive::new(Cursor::new(&data))
+        .and_then(|mut x| Ok(x.by_index_raw(0)?.size()))
+        .ok()?;
+    Some((data, size as usize))
+}
+
+/// Read, validate, optimize and compress a resource. This runs on worker threads, so diagnostics
+/// are returned to be emitted in order.
+fn prepare_resource(
+    resource_root: &RelativePath,
+    archive_path: &RelativePathBuf,
+    resource: &Resource,
+    opt: &BuildOptions,
+) -> PreparedResource {
+    let mut diagnostics = Vec::new();
+    let mut optimized = None;
+
+    let mut bytes = resource.read().unwrap_or_else(|err| {
+        panic!(
+            "failed to read resource {}: {}",
+            diagnostic_path(archive_path, resource).display(),
+            err
+        )
+    });
+
+    let is_png = archive_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    let path = resource_root.join(archive_path);
+    let cache_key = opt.cache.as_ref().map(|_| opt.entry_key(&path, &bytes));
+
+    let mut shape = None;
+    if opt.validate_images && is_png {
+        let cached_check = match (&opt.cache, &cache_key) {
+            (Some(cache), Some(key)) => cache.load_image_check(key),
+            _ => None,
+        };
+        let check = cached_check.unwrap_or_else(|| {
+            let check = check_image(&bytes);
+            if let (Some(cache), Some(key)) = (&opt.cache, &cache_key) {
+                if let Err(err) = cache.save_image_check(key, &check) {
+                    diagnostics.push(
+                        Diagnostic::warning(
+                            "cache-write",
+                            format!("failed to write image check of `{path}` to cache: {err}"),
+                        )
+                        .file(cache.dir()),
+                    );
+                }
+            }
+            check
+        });
+        let os_path = diagnostic_path(archive_path, resource);
+        diagnostics.extend(check.diagnostics.into_iter().map(|x| x.file(&os_path)));
+        shape = check.shape;
+    }
+
+    if let (Some(cache), Some(key)) = (&opt.cache, &cache_key) {
+        if let Some((data, size)) = cached_entry(cache, key) {
+            return PreparedResource {
+                path,
+                data,
+                optimized: (opt.optimize_png && is_png).then_some((bytes.len(), size)),
+                diagnostics,
+                shape,
+                cache_key,
+                cached: true,
+            };
+        }
+    }
+    if opt.optimize_png && is_png {
+        let before = bytes.len();
+        match images::optimize(&bytes) {
+            Ok(Some(x)) => bytes = Cow::Owned(x),
+            // already optimal, keep the file as is
+            Ok(None) => (),
+            Err(err) => diagnostics.push(
+                Diagnostic::warning(
+                    "png-optimize",
+                    format!("failed to optimize image, keeping it unchanged: {err}"),
+                )
+                .file(&diagnostic_path(archive_path, resource)),
+            ),
+        }
+        optimized = Some((before, bytes.len()));
+    }
+
+    let compress = || -> zip::result::ZipResult<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(path.as_str(), opt.file_options(&path))?;
+        zip.write_all(&bytes)?;
+        Ok(zip.finish()?.into_inner())
+    };
+    let data =
+        compress().unwrap_or_else(|err| panic!("failed to compress theme resource {path}: {err}"));
+
+    if let (Some(cache), Some(key)) = (&opt.cache, &cache_key) {
+        if let Err(err) = cache.save_entry(key, &data) {
+            diagnostics.push(
+                Diagnostic::warning(
+                    "cache-write",
+                    format!("failed to write compressed resource `{path}` to cache: {err}"),
+                )
+                .file(cache.dir()),
+            );
+        }
+    }
+
+    PreparedResource {
+        path,
+        data,
+        optimized,
+        diagnostics,
+        shape,
+        cache_key,
+        cached: false,
+    }
+}
+
+/// Map items on `jobs` worker threads, keeping the order of the results
+fn parallel_map<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|x| x.expect("every item should be mapped"))
+        .collect()
+}
+
+fn percent_saved(before: usize, after: usize) -> f64 {
+    match before {
+        0 => 0.0,
+        before => (before - after) as f64 / before as f64 * 100.0,
+    }
+}
+
+/// Convert seconds since the Unix epoch to a ZIP modification time in UTC. ZIP times are between
+/// 1980 and 2107, so other times are clamped.
+fn zip_time(time: i64) -> zip::DateTime {
+    let days = time.div_euclid(86400);
+    let seconds = time.rem_euclid(86400);
+
+    // civil date from days since the epoch, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = match year {
+        ..1980 => return zip::DateTime::default(),
+        2108.. => zip::DateTime::from_date_and_time(2107, 12, 31, 23, 59, 58),
+        _ => zip::DateTime::from_date_and_time(
+            year as u16,
+            month as u8,
+            day as u8,
+            (seconds / 3600) as u8,
+            (seconds % 3600 / 60) as u8,
+            (seconds % 60) as u8,
+        ),
+    };
+    time.expect("date should be in the range of ZIP times")
+}
+
+/// Path to show in diagnostics about a resource; generated resources use their archive path
+fn diagnostic_path(archive_path: &RelativePathBuf, resource: &Resource) -> PathBuf {
+    match resource.path() {
+        Some(path) => path.to_path_buf(),
+        None => archive_path.to_path(""),
+    }
+}
+
+/// Extensions of files that are already compressed, which are stored without recompressing
+const COMPRESSED_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "gif", "webp"];
+/// Extensions of text files, which get maximum compression
+const TEXT_EXTENSIONS: [&str; 7] = ["txt", "reapertheme", "ini", "json", "lua", "md", "xml"];
+
+/// Compression method of files in the theme archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Stored,
+    Deflated,
+}
+
+#[derive(Clone)]
 pub struct BuildOptions {
     overwrite: bool,
+    /// compression method of every file, instead of choosing one by file type
+    compression: Option<Compression>,
+    /// deflate level of deflated files, instead of choosing one by file type
+    compression_level: Option<i32>,
+    /// number of threads compressing resources, instead of the number of CPUs
+    jobs: Option<usize>,
+    validate_images: bool,
+    optimize_png: bool,
+    /// cache of compressed resources, reused when their contents are unchanged
+    cache: Option<Cache>,
+    /// modification time of the files in the archive in seconds since the Unix epoch, instead of
+    /// the current time
+    build_time: Option<i64>,
 }
 
 impl Default for BuildOptions {
     fn default() -> Self {
-        Self { overwrite: false }
+        Self {
+            overwrite: false,
+            compression: None,
+            compression_level: None,
+            jobs: None,
+            validate_images: true,
+            optimize_png: false,
+            cache: None,
+            build_time: None,
+        }
     }
 }
 
@@ -70,6 +412,93 @@ impl BuildOptions {
         self.overwrite = x;
         self
     }
+
+    pub fn compression(mut self, x: Option<Compression>) -> Self {
+        self.compression = x;
+        self
+    }
+
+    pub fn compression_level(mut self, x: Option<i32>) -> Self {
+        self.compression_level = x;
+        self
+    }
+
+    pub fn jobs(mut self, x: Option<usize>) -> Self {
+        self.jobs = x;
+        self
+    }
+
+    /// Options of a file in the archive, with the build time as its modification time if given
+    fn file_options(&self, path: &RelativePath) -> zip::write::FileOptions {
+        let options = self.compression_options(path);
+        match self.build_time {
+            Some(time) => options.last_modified_time(zip_time(time)),
+            None => options,
+        }
+    }
+
+    /// Compression of a file in the archive. By default, already compressed files like PNG images
+    /// are stored as is, text files get maximum compression, and other files use level 6.
+    fn compression_options(&self, path: &RelativePath) -> zip::write::FileOptions {
+        let extension = path.extension().unwrap_or_default().to_ascii_lowercase();
+        let method =
+            self.compression
+                .unwrap_or(if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+                    Compression::Stored
+                } else {
+                    Compression::Deflated
+                });
+        match method {
+            Compression::Stored => zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored),
+            Compression::Deflated => {
+                let level = self.compression_level.unwrap_or(
+                    if TEXT_EXTENSIONS.contains(&extension.as_str()) {
+                        9
+                    } else {
+                        6
+                    },
+                );
+                zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Deflated)
+                    .compression_level(Some(level))
+            }
+        }
+    }
+
+    pub fn validate_images(mut self, x: bool) -> Self {
+        self.validate_images = x;
+        self
+    }
+
+    pub fn optimize_png(mut self, x: bool) -> Self {
+        self.optimize_png = x;
+        self
+    }
+
+    pub fn cache(mut self, x: Option<Cache>) -> Self {
+        self.cache = x;
+        self
+    }
+
+    pub fn build_time(mut self, x: Option<i64>) -> Self {
+        self.build_time = x;
+        self
+    }
+
+    /// Key of a compressed resource in the cache, from its path, contents and the options that
+    /// change how it is packed
+    fn entry_key(&self, path: &RelativePath, bytes: &[u8]) -> String {
+        cache::hash_parts(&[
+            env!("CARGO_PKG_VERSION"),
+            path.as_str(),
+            &cache::hash(bytes),
+            &format!(
+                "{:?} {:?} {:?} {:?}",
+                self.optimize_png, self.compression, self.compression_level, self.build_time
+            ),
+        ])
+    }
 }
 
 #[derive(Error, Debug)]
@@ -78,6 +507,16 @@ pub enum BuildError {
     PathExistsError(PathBuf),
 }
 
+impl From<&BuildError> for Diagnostic {
+    fn from(err: &BuildError) -> Self {
+        match err {
+            BuildError::PathExistsError(path) => {
+                Diagnostic::error("path-exists", "the path already exists").file(path)
+            }
+        }
+    }
+}
+
 impl Theme {
     pub fn build(&self, path: &Path, opt: &BuildOptions) -> Result<(), BuildError> {
         if (path.is_file() && !opt.overwrite) || path.is_dir() {
@@ -95,19 +534,26 @@ impl Theme {
                 .unwrap_or(Default::default())
                 .to_string_lossy();
             if path_stem != self.name {
-                warn!("Output theme file has a different name than the theme; REAPER may not load the theme correctly!");
+                Diagnostic::warning(
+                    "theme-name-mismatch",
+                    "Output theme file has a different name than the theme; REAPER may not load the theme correctly!",
+                )
+                .file(path)
+                .emit();
             }
             if extension.to_ascii_lowercase() != "reaperthemezip" {
-                warn!("Output theme file does not end with '.ReaperThemeZip'; REAPER may not be able to load the theme!");
+                Diagnostic::warning(
+                    "theme-extension",
+                    "Output theme file does not end with '.ReaperThemeZip'; REAPER may not be able to load the theme!",
+                )
+                .file(path)
+                .emit();
             }
         }
 
         // create ZIP file
         let file = std::fs::File::create(path).unwrap();
         let mut zip = zip::ZipWriter::new(file);
-        let file_options = zip::write::FileOptions::default()
-            .compression_method(zip::CompressionMethod::Deflated)
-            .compression_level(Some(6));
 
         // write .ReaperTheme
         {
@@ -117,17 +563,18 @@ impl Theme {
                 x
             };
 
-            zip.start_file(reapertheme_path.as_str(), file_options)
-                .expect(&format!(
-                    "failed to write theme .ReaperTheme file: {}",
-                    &reapertheme_path
-                ));
+            zip.start_file(
+                reapertheme_path.as_str(),
+                opt.file_options(&reapertheme_path),
+            )
+            .unwrap_or_else(|err| {
+                panic!("failed to write theme .ReaperTheme file {reapertheme_path}: {err}")
+            });
 
             zip.write_all(self.reapertheme().as_bytes())
-                .expect(&format!(
-                    "failed to write theme .ReaperTheme file: {}",
-                    &reapertheme_path
-                ));
+                .unwrap_or_else(|err| {
+                    panic!("failed to write theme .ReaperTheme file {reapertheme_path}: {err}")
+                });
         }
 
         // write rtconfig.txt
@@ -136,36 +583,108 @@ impl Theme {
                 .join("rtconfig.txt")
                 .normalize();
 
-            zip.start_file(rtconfig_path.as_str(), file_options)
-                .expect(&format!(
-                    "failed to write theme rtconfig.txt: {}",
-                    &rtconfig_path
-                ));
-            zip.write_all(self.rtconfig.as_bytes()).expect(&format!(
-                "failed to write theme rtconfig.txt: {}",
-                &rtconfig_path
-            ));
+            zip.start_file(rtconfig_path.as_str(), opt.file_options(&rtconfig_path))
+                .unwrap_or_else(|err| {
+                    panic!("failed to write theme rtconfig.txt {rtconfig_path}: {err}")
+                });
+            zip.write_all(self.rtconfig.as_bytes())
+                .unwrap_or_else(|err| {
+                    panic!("failed to write theme rtconfig.txt {rtconfig_path}: {err}")
+                });
         }
 
-        // write resources
+        // write resources. Each file is validated, optimized and compressed into its own small
+        // archive on worker threads, then copied into the theme archive sorted by path.
         {
             let resource_root = RelativePathBuf::from(&self.name).normalize();
 
-            for (archive_path, os_path) in self.resources.iter() {
-                let archive_path = resource_root.join(archive_path);
-
-                let mut resource = std::fs::File::open(os_path.as_path())
-                    .expect(&format!("failed to read resource {}", os_path.display()));
-
-                zip.start_file(archive_path.as_str(), file_options)
-                    .expect(&format!(
-                        "failed to write theme resource: {}",
-                        &archive_path
-                    ));
-                std::io::copy(&mut resource, &mut zip).expect(&format!(
-                    "failed to write theme resource: {}",
-                    os_path.display()
-                ));
+            let mut resources: Vec<_> = self.resources.iter().collect();
+            resources.sort_by(|a, b| a.0.cmp(b.0));
+            let jobs = opt.jobs.unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|x| x.get())
+                    .unwrap_or(1)
+            });
+            let entries = parallel_map(&resources, jobs, |(archive_path, resource)| {
+                prepare_resource(&resource_root, archive_path, resource, opt)
+            });
+            let shapes: HashMap<_, _> = resources
+                .iter()
+                .zip(&entries)
+                .filter_map(|((archive_path, _), entry)| {
+                    Some((archive_path.normalize(), entry.shape?))
+                })
+                .collect();
+
+            // sizes of PNG files before and after optimizing
+            let mut png_count = 0;
+            let mut png_before = 0;
+            let mut png_after = 0;
+            let mut used_cache_keys = HashSet::new();
+            let mut cached_count = 0;
+
+            for entry in entries {
+                if entry.cached {
+                    cached_count += 1;
+                }
+                used_cache_keys.extend(entry.cache_key.clone());
+                for diagnostic in &entry.diagnostics {
+                    diagnostic.emit();
+                }
+                if let Some((before, after)) = entry.optimized {
+                    if after < before {
+                        Diagnostic::note(
+                            "png-optimized",
+                            format!(
+                                "optimized `{}`: {} -> {} bytes ({:.1}% smaller)",
+                                entry.path,
+                                before,
+                                after,
+                                percent_saved(before, after)
+                            ),
+                        )
+                        .emit();
+                    }
+                    png_count += 1;
+                    png_before += before;
+                    png_after += after;
+                }
+
+                let mut copy = || -> zip::result::ZipResult<()> {
+                    let mut archive = zip::ZipArchive::new(Cursor::new(&entry.data))?;
+                    let file = archive.by_index_raw(0)?;
+                    zip.raw_copy_file(file)
+                };
+                copy().unwrap_or_else(|err| {
+                    panic!("failed to write theme resource {}: {}", entry.path, err)
+                });
+            }
+
+            for diagnostic in scale_problems(&resources, &shapes) {
+                diagnostic.emit();
+            }
+
+            if opt.optimize_png {
+                Diagnostic::note(
+                    "png-optimized-total",
+                    format!(
+                        "optimized {} PNG files: {} -> {} bytes ({:.1}% smaller)",
+                        png_count,
+                        png_before,
+                        png_after,
+                        percent_saved(png_before, png_after)
+                    ),
+                )
+                .emit();
+            }
+
+            if let Some(cache) = &opt.cache {
+                info!(
+                    "reused {} of {} compressed resources from the cache",
+                    cached_count,
+                    resources.len()
+                );
+                cache.prune_entries(&used_cache_keys);
             }
         }
 
@@ -174,3 +693,270 @@ impl Theme {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::io::Read;
+
+    #[test]
+    fn test_build_generated_resources() {
+        let dir = TempDir::new("build");
+        std::fs::write(dir.join("file.txt"), "from disk").unwrap();
+
+        let mut resources = ResourceMap::new();
+        resources.insert("file.txt".into(), Resource::File(dir.join("file.txt")));
+        resources.insert("a/data.bin".into(), Resource::Generated(vec![0, 1, 2]));
+        resources.insert(
+            "readme.txt".into(),
+            Resource::Generated(b"generated".to_vec()),
+        );
+
+        let path = dir.join("test.ReaperThemeZip");
+        let theme = Theme::new("test", "", Ini::new(), resources);
+        theme
+            .build(&path, &BuildOptions::default().validate_images(false))
+            .unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let mut read = |name: &str| {
+            let mut buf = Vec::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            buf
+        };
+        assert_eq!(read("test/file.txt"), b"from disk");
+        assert_eq!(read("test/a/data.bin"), [0, 1, 2]);
+        assert_eq!(read("test/readme.txt"), b"generated");
+    }
+
+    #[test]
+    fn test_parallel_map() {
+        let items: Vec<u32> = (0..100).collect();
+        for jobs in [0, 1, 4, 200] {
+            let result = parallel_map(&items, jobs, |x| x * 2);
+            assert_eq!(result, items.iter().map(|x| x * 2).collect::<Vec<_>>());
+        }
+        assert!(parallel_map(&Vec::<u32>::new(), 4, |x| *x).is_empty());
+    }
+
+    #[test]
+    fn test_build_parallel() {
+        let dir = TempDir::new("parallel");
+
+        let resources: ResourceMap = (0..50)
+            .map(|i| {
+                (
+                    RelativePathBuf::from(format!("file{i:02}.txt")),
+                    Resource::Generated(i.to_string().repeat(i).into()),
+                )
+            })
+            .collect();
+        let path = dir.join("test.ReaperThemeZip");
+        let theme = Theme::new("test", "", Ini::new(), resources);
+        theme
+            .build(&path, &BuildOptions::default().jobs(Some(4)))
+            .unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let names: Vec<String> = (0..archive.len())
+            .map(|i| archive.by_index(i).unwrap().name().to_string())
+            .collect();
+        let mut expected = vec!["test.ReaperTheme".to_string(), "test/rtconfig.txt".into()];
+        expected.extend((0..50).map(|i| format!("test/file{i:02}.txt")));
+        assert_eq!(names, expected);
+
+        let mut contents = String::new();
+        archive
+            .by_name("test/file07.txt")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "7777777");
+    }
+
+    #[test]
+    fn test_build_compression() {
+        let dir = TempDir::new("compression");
+
+        let mut resources = ResourceMap::new();
+        resources.insert("knob.png".into(), Resource::Generated(vec![0; 100]));
+        resources.insert("data.bin".into(), Resource::Generated(vec![0; 100]));
+        resources.insert(
+            "readme.txt".into(),
+            Resource::Generated("text".repeat(100).into()),
+        );
+        let theme = Theme::new("test", "", Ini::new(), resources);
+
+        let compression = |opt: &BuildOptions| {
+            let path = dir.join("test.ReaperThemeZip");
+            theme
+                .build(&path, &opt.clone().overwrite(true).validate_images(false))
+                .unwrap();
+            let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+            let mut methods: Vec<_> = (0..archive.len())
+                .map(|i| {
+                    let file = archive.by_index(i).unwrap();
+                    (file.name().to_string(), file.compression())
+                })
+                .collect();
+            methods.sort_by(|a, b| a.0.cmp(&b.0));
+            methods
+        };
+
+        use zip::CompressionMethod::{Deflated, Stored};
+        assert_eq!(
+            compression(&BuildOptions::default()),
+            vec![
+                ("test.ReaperTheme".to_string(), Deflated),
+                ("test/data.bin".to_string(), Deflated),
+                ("test/knob.png".to_string(), Stored),
+                ("test/readme.txt".to_string(), Deflated),
+                ("test/rtconfig.txt".to_string(), Deflated),
+            ]
+        );
+        assert!(
+            compression(&BuildOptions::default().compression(Some(Compression::Stored)))
+                .iter()
+                .all(|(_, method)| *method == Stored)
+        );
+        assert!(
+            compression(&BuildOptions::default().compression(Some(Compression::Deflated)))
+                .iter()
+                .all(|(_, method)| *method == Deflated)
+        );
+    }
+
+    #[test]
+    fn test_build_cache() {
+        let dir = TempDir::new("build-cache");
+        let cache = Cache::new(&dir.join("cache"));
+        let opt = BuildOptions::default()
+            .overwrite(true)
+            .cache(Some(cache.clone()));
+
+        let build = |text: &str| {
+            let mut resources = ResourceMap::new();
+            resources.insert("a.txt".into(), Resource::Generated(b"unchanged".to_vec()));
+            resources.insert("b.txt".into(), Resource::Generated(text.into()));
+            let path = dir.join("test.ReaperThemeZip");
+            Theme::new("test", "", Ini::new(), resources)
+                .build(&path, &opt)
+                .unwrap();
+
+            let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+            let mut contents = String::new();
+            archive
+                .by_name("test/b.txt")
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            contents
+        };
+        let entries = || {
+            std::fs::read_dir(dir.join("cache/entries"))
+                .unwrap()
+                .count()
+        };
+
+        assert_eq!(build("first"), "first");
+        assert_eq!(entries(), 2);
+        assert_eq!(build("second"), "second");
+        // the entry of the old contents is pruned
+        assert_eq!(entries(), 2);
+
+        let key = opt.entry_key(RelativePath::new("test/a.txt"), b"unchanged");
+        assert!(cached_entry(&cache, &key).is_some());
+    }
+
+    #[test]
+    fn test_build_cache_image_check() {
+        let dir = TempDir::new("build-cache-image");
+        let cache = Cache::new(&dir.join("cache"));
+        let opt = BuildOptions::default().cache(Some(cache.clone()));
+        let root = RelativePathBuf::from("test");
+        let archive_path = RelativePathBuf::from("a.png");
+        let resource = Resource::Generated(b"not a png".to_vec());
+
+        let prepared = prepare_resource(&root, &archive_path, &resource, &opt);
+        assert_eq!(prepared.shape, None);
+        assert_eq!(prepared.diagnostics.len(), 1);
+        assert_eq!(prepared.diagnostics[0].code, "png-invalid");
+
+        // on a hit the saved check is used instead of decoding the file again
+        let key = prepared.cache_key.unwrap();
+        let shape = images::Shape {
+            width: 1,
+            height: 2,
+            pink_border: false,
+        };
+        cache
+            .save_image_check(
+                &key,
+                &ImageCheck {
+                    diagnostics: Vec::new(),
+                    shape: Some(shape),
+                },
+            )
+            .unwrap();
+        let prepared = prepare_resource(&root, &archive_path, &resource, &opt);
+        assert!(prepared.cached);
+        assert_eq!(prepared.shape, Some(shape));
+        assert!(prepared.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_zip_time() {
+        let time = zip_time(1_700_000_000);
+        assert_eq!((time.year(), time.month(), time.day()), (2023, 11, 14));
+        assert_eq!((time.hour(), time.minute(), time.second()), (22, 13, 20));
+        let time = zip_time(951_782_400);
+        assert_eq!((time.year(), time.month(), time.day()), (2000, 2, 29));
+        assert_eq!(zip_time(0).year(), 1980);
+        assert_eq!(zip_time(i64::MAX / 2).year(), 2107);
+    }
+
+    #[test]
+    fn test_build_reproducible() {
+        let dir = TempDir::new("reproducible");
+
+        let build = |name: &str| {
+            let resources: ResourceMap = (0..10)
+                .map(|i| {
+                    (
+                        RelativePathBuf::from(format!("file{i}.txt")),
+                        Resource::Generated(i.to_string().into()),
+                    )
+                })
+                .collect();
+            let path = dir.join(name);
+            Theme::new("test", "set a b", Ini::new(), resources)
+                .build(
+                    &path,
+                    &BuildOptions::default()
+                        .build_time(Some(1_700_000_000))
+                        .jobs(Some(3)),
+                )
+                .unwrap();
+            std::fs::read(path).unwrap()
+        };
+
+        let first = build("a.ReaperThemeZip");
+        assert_eq!(build("b.ReaperThemeZip"), first);
+
+        // entries are dated with the build time, not the current time
+        let expected = zip_time(1_700_000_000);
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(first)).unwrap();
+        for i in 0..archive.len() {
+            let time = archive.by_index(i).unwrap().last_modified();
+            assert_eq!(
+                (time.datepart(), time.timepart()),
+                (expected.datepart(), expected.timepart())
+            );
+        }
+    }
+}
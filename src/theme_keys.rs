@@ -0,0 +1,376 @@
+/// Type of the value of a `[color theme]` key in a *.ReaperTheme file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Color,
+    Blend,
+}
+
+/// Keys of the `[color theme]` section of REAPER's default theme that take a color
+const COLOR_KEYS: &[&str] = &[
+    // main window
+    "col_main_bg2",
+    "col_main_text2",
+    "col_main_textshadow",
+    "col_main_3dhl",
+    "col_main_3dsh",
+    "col_main_resize2",
+    "col_main_text",
+    "col_main_bg",
+    "col_main_editbk",
+    "col_nodarkmodemiscwnd",
+    "col_transport_editbk",
+    "col_toolbar_text",
+    "col_toolbar_text_on",
+    "col_toolbar_frame",
+    "toolbararmed_color",
+    "io_text",
+    "io_3dhl",
+    "io_3dsh",
+    "genlist_bg",
+    "genlist_fg",
+    "genlist_grid",
+    "genlist_selbg",
+    "genlist_selfg",
+    "genlist_seliabg",
+    "genlist_seliafg",
+    "genlist_hilite",
+    "genlist_hilite_sel",
+    "col_buttonbg",
+    // tracks and arrange view
+    "col_tcp_text",
+    "col_tcp_textsel",
+    "col_seltrack",
+    "col_seltrack2",
+    "tcplocked_color",
+    "col_tracklistbg",
+    "col_mixerbg",
+    "col_arrangebg",
+    "arrange_vgrid",
+    "col_fadearm",
+    "col_fadearm2",
+    "col_fadearm3",
+    "col_tl_fg",
+    "col_tl_fg2",
+    "col_tl_bg",
+    "col_tl_bgsel",
+    "col_tl_bgsel2",
+    "col_trans_bg",
+    "col_trans_fg",
+    "playrate_edited",
+    // media items
+    "selitem_dot",
+    "col_mi_label",
+    "col_mi_label_sel",
+    "col_mi_label_float",
+    "col_mi_label_float_sel",
+    "col_mi_bg",
+    "col_mi_bg2",
+    "col_tr1_itembgsel",
+    "col_tr2_itembgsel",
+    "col_tr1_peaks",
+    "col_tr2_peaks",
+    "col_tr1_ps2",
+    "col_tr2_ps2",
+    "col_peaksedge",
+    "col_peaksedge2",
+    "col_peaksedgesel",
+    "col_peaksedgesel2",
+    "col_peaksfade",
+    "col_peaksfade2",
+    "col_mi_fades",
+    "fadezone_color",
+    "fadearea_color",
+    "col_mi_fade2",
+    "item_grouphl",
+    "col_offlinetext",
+    "col_stretchmarker",
+    "col_stretchmarker_h0",
+    "col_stretchmarker_h1",
+    "col_stretchmarker_h2",
+    "col_stretchmarker_b",
+    "col_stretchmarkerm",
+    "col_stretchmarker_text",
+    "col_stretchmarker_tm",
+    "take_marker",
+    "take_marker_sel",
+    "selitem_tag",
+    "activetake_tag",
+    // track backgrounds, lanes and overlays
+    "col_tr1_bg",
+    "col_tr2_bg",
+    "selcol_tr1_bg",
+    "selcol_tr2_bg",
+    "track_lane_tabcol",
+    "track_lanesolo_tabcol",
+    "track_lanesolo_text",
+    "track_lane_gutter",
+    "col_tr1_divline",
+    "col_tr2_divline",
+    "col_envlane1_divline",
+    "col_envlane2_divline",
+    "mute_overlay_col",
+    "inactive_take_overlay_col",
+    "locked_overlay_col",
+    "marquee_fill",
+    "marquee_outline",
+    "marqueezoom_fill",
+    "marqueezoom_outline",
+    "areasel_fill",
+    "areasel_outline",
+    "linkedlane_fill",
+    "linkedlane_outline",
+    "linkedlane_unsynced",
+    "col_cursor",
+    "col_cursor2",
+    "playcursor_color",
+    "col_gridlines2",
+    "col_gridlines3",
+    "col_gridlines",
+    "guideline_color",
+    // markers and regions
+    "region",
+    "region_lane_bg",
+    "region_lane_text",
+    "marker",
+    "marker_lane_bg",
+    "marker_lane_text",
+    "col_tsigmark",
+    "ts_lane_bg",
+    "ts_lane_text",
+    "timesig_sel_bg",
+    // routing and meters
+    "col_routinghl1",
+    "col_routinghl2",
+    "col_routingact",
+    "col_vudoint",
+    "col_vuclip",
+    "col_vutop",
+    "col_vumid",
+    "col_vubot",
+    "col_vuintcol",
+    "vu_gr_bgcol",
+    "vu_gr_fgcol",
+    "col_vumidi",
+    "col_vuind1",
+    "col_vuind2",
+    "col_vuind3",
+    "col_vuind4",
+    // mixer
+    "mcp_sends_normal",
+    "mcp_sends_muted",
+    "mcp_send_midihw",
+    "mcp_sends_levels",
+    "mcp_fx_normal",
+    "mcp_fx_bypassed",
+    "mcp_fx_offlined",
+    "mcp_fxparm_normal",
+    "mcp_fxparm_bypassed",
+    "mcp_fxparm_offlined",
+    "tcp_list_scrollbar",
+    "tcp_list_scrollbar_mouseover",
+    "mcp_list_scrollbar",
+    "mcp_list_scrollbar_mouseover",
+    // MIDI editor
+    "midi_rulerbg",
+    "midi_rulerfg",
+    "midi_grid2",
+    "midi_grid3",
+    "midi_grid1",
+    "midi_trackbg1",
+    "midi_trackbg2",
+    "midi_trackbg_outer1",
+    "midi_trackbg_outer2",
+    "midi_selpitch1",
+    "midi_selpitch2",
+    "midi_selbg",
+    "midi_gridhc",
+    "midi_gridh",
+    "midi_ccbut",
+    "midi_ccbut_text",
+    "midi_ccbut_arrow",
+    "midioct",
+    "midi_inline_trackbg1",
+    "midi_inline_trackbg2",
+    "midioct_inline",
+    "midi_endpt",
+    "midi_notebg",
+    "midi_notefg",
+    "midi_notemute",
+    "midi_notemute_sel",
+    "midi_itemctl",
+    "midi_ofsn",
+    "midi_ofsnsel",
+    "midi_editcurs",
+    "midi_pkey1",
+    "midi_pkey2",
+    "midi_pkey3",
+    "midi_noteon_flash",
+    "midi_leftbg",
+    "midifont_col_light_unsel",
+    "midifont_col_dark_unsel",
+    "midifont_col_light",
+    "midifont_col_dark",
+    "score_bg",
+    "score_fg",
+    "score_sel",
+    "score_timesel",
+    "score_loop",
+    "midieditorlist_bg",
+    "midieditorlist_fg",
+    "midieditorlist_grid",
+    "midieditorlist_selbg",
+    "midieditorlist_selfg",
+    "midieditorlist_seliabg",
+    "midieditorlist_seliafg",
+    "midieditorlist_bg2",
+    "midieditorlist_fg2",
+    "midieditorlist_selbg2",
+    "midieditorlist_selfg2",
+    // media explorer and docker
+    "col_explorer_sel",
+    "col_explorer_seledge",
+    "explorer_grid",
+    "explorer_pitchtext",
+    "docker_shadow",
+    "docker_selface",
+    "docker_unselface",
+    "docker_text",
+    "docker_text_sel",
+    "docker_bg",
+    "windowtab_bg",
+    // envelopes
+    "auto_item_unsel",
+    "col_env1",
+    "col_env2",
+    "col_env3",
+    "col_env4",
+    "col_env5",
+    "col_env6",
+    "col_env7",
+    "col_env8",
+    "col_env9",
+    "col_env10",
+    "col_env11",
+    "col_env12",
+    "col_env13",
+    "col_env14",
+    "col_env15",
+    "col_env16",
+    "env_item_vol",
+    "env_item_pan",
+    "env_item_mute",
+    "env_item_pitch",
+    // routing matrix and wiring diagram
+    "wiring_grid2",
+    "wiring_grid",
+    "wiring_border",
+    "wiring_tbg",
+    "wiring_ticon",
+    "wiring_recbg",
+    "wiring_recitem",
+    "wiring_media",
+    "wiring_recv",
+    "wiring_send",
+    "wiring_fader",
+    "wiring_parent",
+    "wiring_parentwire_border",
+    "wiring_parentwire_master",
+    "wiring_parentwire_folder",
+    "wiring_pin_normal",
+    "wiring_pin_connected",
+    "wiring_pin_disconnected",
+    "wiring_horz_col",
+    "wiring_sendwire",
+    "wiring_hwout",
+    "wiring_recinput",
+    "wiring_hwoutwire",
+    "wiring_recinputwire",
+    "wiring_hwout_muted",
+    "wiring_sendwire_muted",
+    "wiring_hwoutwire_muted",
+    "wiring_recinputwire_muted",
+];
+
+/// Keys of the `[color theme]` section of REAPER's default theme that take a blend mode
+const BLEND_KEYS: &[&str] = &[
+    "toolbararmed_drawmode",
+    "tcplocked_drawmode",
+    "timesel_drawmode",
+    "itembg_drawmode",
+    "cc_chase_drawmode",
+    "fadezone_drawmode",
+    "fadearea_drawmode",
+    "col_mi_fade2_drawmode",
+    "track_lane_gutter_drawmode",
+    "mute_overlay_mode",
+    "inactive_take_overlay_mode",
+    "locked_overlay_mode",
+    "marquee_drawmode",
+    "marqueezoom_drawmode",
+    "areasel_drawmode",
+    "areasel_outlinemode",
+    "linkedlane_fillmode",
+    "linkedlane_outlinemode",
+    "linkedlane_unsynced_mode",
+    "playcursor_drawmode",
+    "col_gridlines2dm",
+    "col_gridlines3dm",
+    "col_gridlines1dm",
+    "guideline_drawmode",
+    "tcp_list_scrollbar_mode",
+    "tcp_list_scrollbar_mouseover_mode",
+    "mcp_list_scrollbar_mode",
+    "mcp_list_scrollbar_mouseover_mode",
+    "midi_griddm2",
+    "midi_griddm3",
+    "midi_griddm1",
+    "midi_selbg_drawmode",
+    "midi_gridhcdm",
+    "midi_gridhdm",
+    "midi_itemctl_mode",
+    "midifont_mode_unsel",
+    "midifont_mode",
+    "col_explorer_seldm",
+];
+
+/// Number of track group colors, `group_0` to `group_63`
+const GROUP_COLORS: u32 = 64;
+
+/// Type of the value of a key in the `[color theme]` section, or `None` for keys that aren't in
+/// REAPER's default theme
+pub fn value_type(key: &str) -> Option<ValueType> {
+    let is_group = key
+        .strip_prefix("group_")
+        .and_then(|x| x.parse::<u32>().ok())
+        .is_some_and(|x| x < GROUP_COLORS && key == format!("group_{x}"));
+    if is_group || COLOR_KEYS.contains(&key) {
+        Some(ValueType::Color)
+    } else if BLEND_KEYS.contains(&key) {
+        Some(ValueType::Blend)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_type() {
+        assert_eq!(value_type("col_tr1_bg"), Some(ValueType::Color));
+        assert_eq!(value_type("playcursor_color"), Some(ValueType::Color));
+        assert_eq!(value_type("group_0"), Some(ValueType::Color));
+        assert_eq!(value_type("group_63"), Some(ValueType::Color));
+        assert_eq!(value_type("group_64"), None);
+        assert_eq!(value_type("group_01"), None);
+        assert_eq!(value_type("midi_griddm1"), Some(ValueType::Blend));
+        assert_eq!(value_type("timesel_drawmode"), Some(ValueType::Blend));
+        assert_eq!(value_type("col_unknown"), None);
+
+        // every key has one type
+        for key in COLOR_KEYS {
+            assert!(!BLEND_KEYS.contains(key), "{key}");
+        }
+    }
+}